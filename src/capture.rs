@@ -0,0 +1,256 @@
+use std::cmp::min;
+use std::thread;
+use std::ptr;
+use std::ffi::CStr;
+use libc;
+use pcap::{Capture, Active, Offline, Activated, PacketHeader, Error};
+
+use engine::types::*;
+
+// Max time spent waiting for a replayed packet, like the live capture timeout
+const REPLAY_WAIT_MS: u64 = 10;
+
+pub struct Packet {
+  // the pcap link type
+  pub linktype: i32,
+  pub header: PacketHeader,
+  pub data: Vec<u8>,
+}
+
+// A live capture or a capture file replayed according to the packets timestamps
+pub struct PacketSource {
+  cap: Capture<dyn Activated>,
+  linktype: i32,
+  offline: bool,
+  speed: f64,
+  // first packet timestamp and the wall clock time it was read at
+  start: Option<(Timeval, Timeval)>,
+  last_ts: Timeval,
+  pending: Option<Packet>,
+  eof: bool,
+}
+
+impl PacketSource {
+  pub fn live(cap: Capture<Active>) -> PacketSource {
+    PacketSource::new(cap.into(), false, 0.)
+  }
+
+  /* speed is a multiplier of the original speed, 0 to read as fast as possible */
+  pub fn offline(cap: Capture<Offline>, speed: f64) -> PacketSource {
+    PacketSource::new(cap.into(), true, speed)
+  }
+
+  fn new(cap: Capture<dyn Activated>, offline: bool, speed: f64) -> PacketSource {
+    PacketSource {
+      linktype: cap.get_datalink().0,
+      cap: cap,
+      offline: offline,
+      speed: speed,
+      start: None,
+      last_ts: Default::default(),
+      pending: None,
+      eof: false,
+    }
+  }
+
+  /* an empty filter captures all the packets */
+  pub fn set_filter(&mut self, filter: &str) -> Result<(), String> {
+    self.cap.filter(filter).map_err(|err| format!("Invalid filter \"{}\": {}", filter, err))
+  }
+
+  pub fn is_offline(&self) -> bool {
+    self.offline
+  }
+
+  /* packets received and dropped by the kernel, only available when live */
+  pub fn stats(&mut self) -> Option<(usize, usize)> {
+    if self.offline {
+      return None;
+    }
+
+    match self.cap.stats() {
+      Ok(stat) => {
+        // both u32, their sum may not fit
+        let dropped = stat.dropped as u64 + stat.if_dropped as u64;
+        Some((stat.received as usize, dropped as usize))
+      },
+      Err(_) => None,
+    }
+  }
+
+  pub fn is_eof(&self) -> bool {
+    self.eof && self.pending.is_none()
+  }
+
+  /* current time: wall clock when live, the replay position otherwise */
+  pub fn now(&self) -> Timeval {
+    if !self.offline {
+      return SystemTime::now().into();
+    }
+
+    match self.start {
+      Some((first_ts, started)) if (self.speed > 0.) && !self.is_eof() => {
+        let elapsed = Timeval::from(SystemTime::now()) - started;
+        first_ts.add_secs(elapsed * self.speed)
+      },
+      _ => self.last_ts,
+    }
+  }
+
+  fn read_packet(&mut self) {
+    match self.cap.next() {
+      Ok(packet) => {
+        let ts: Timeval = packet.header.ts.into();
+
+        if self.start.is_none() {
+          self.start = Some((ts, SystemTime::now().into()));
+        }
+
+        self.pending = Some(Packet {
+          linktype: self.linktype,
+          header: *packet.header,
+          data: packet.data.to_vec(),
+        });
+      },
+      Err(Error::TimeoutExpired) => (),
+      Err(Error::NoMorePackets) => self.eof = true,
+      Err(err) => {
+        if self.offline {
+          error!("Capture file read error: {:?}", err);
+          self.eof = true;
+        }
+      },
+    }
+  }
+
+  /* returns the next packet, or None if no packet is due yet */
+  pub fn next(&mut self) -> Option<Packet> {
+    if self.pending.is_none() && !self.eof {
+      self.read_packet();
+    }
+
+    let wait = match self.pending {
+      Some(ref packet) if self.offline && (self.speed > 0.) => {
+        Timeval::from(packet.header.ts) - self.now()
+      },
+      Some(_) => 0.,
+      None => {
+        if self.eof {
+          thread::sleep(Duration::from_millis(REPLAY_WAIT_MS));
+        }
+        return None;
+      },
+    };
+
+    if wait > 0. {
+      let wait_ms = min((wait * 1000_f64 / self.speed) as u64, REPLAY_WAIT_MS);
+      thread::sleep(Duration::from_millis(wait_ms));
+      return None;
+    }
+
+    let packet = self.pending.take().unwrap();
+    self.last_ts = packet.header.ts.into();
+    Some(packet)
+  }
+}
+
+unsafe fn sockaddr_ip(sa: *const libc::sockaddr) -> Option<IpAddr> {
+  match (*sa).sa_family as i32 {
+    libc::AF_INET => {
+      let sin = &*(sa as *const libc::sockaddr_in);
+      Some(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)).into())
+    },
+    libc::AF_INET6 => {
+      let sin6 = &*(sa as *const libc::sockaddr_in6);
+      Some(Ipv6Addr::from(sin6.sin6_addr.s6_addr).into())
+    },
+    _ => None,
+  }
+}
+
+fn netmask_prefix(mask: &IpAddr) -> u8 {
+  match *mask {
+    IpAddr::V4(mask) => u32::from(mask).count_ones() as u8,
+    IpAddr::V6(mask) => u128::from(mask).count_ones() as u8,
+  }
+}
+
+/* the networks of the interface addresses, empty if it has none (e.g. "any") */
+pub fn interface_networks(name: &str) -> Vec<IpNetwork> {
+  let mut networks = Vec::new();
+  let mut ifaddrs: *mut libc::ifaddrs = ptr::null_mut();
+
+  unsafe {
+    if libc::getifaddrs(&mut ifaddrs) != 0 {
+      return networks;
+    }
+
+    let mut ifa = ifaddrs;
+
+    while !ifa.is_null() {
+      let entry = &*ifa;
+      ifa = entry.ifa_next;
+
+      if entry.ifa_addr.is_null() || entry.ifa_netmask.is_null() || (CStr::from_ptr(entry.ifa_name).to_bytes() != name.as_bytes()) {
+        continue;
+      }
+
+      if let (Some(addr), Some(mask)) = (sockaddr_ip(entry.ifa_addr), sockaddr_ip(entry.ifa_netmask)) {
+        networks.push(IpNetwork::from_addr(&addr, netmask_prefix(&mask)));
+      }
+    }
+
+    libc::freeifaddrs(ifaddrs);
+  }
+
+  networks
+}
+
+#[cfg(test)]
+mod tests {
+  use engine::packet_handler::PacketHandler;
+  use super::*;
+
+  // two UDP flows: 10.0.0.1:1000 <-> 10.0.0.2:53 (a request and its reply at
+  // 1000s) and 10.0.0.1:1001 -> 10.0.0.3:53 (at 1001s and 1100s)
+  const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/udp_flows.pcap");
+
+  #[test]
+  fn replay_capture_file() {
+    let mut source = PacketSource::offline(Capture::from_file(FIXTURE).unwrap(), 0.);
+    let mut handler = PacketHandler::new(60, 10, false, Vec::new());
+    let mut num_packets = 0;
+
+    while !source.is_eof() {
+      if let Some(packet) = source.next() {
+        handler.process_packet(packet.linktype, &packet.header, &packet.data);
+        num_packets += 1;
+      }
+    }
+
+    assert_eq!(num_packets, 4);
+    // the replay position, not the wall clock
+    assert_eq!(source.now(), Timeval { sec: 1100, usec: 0 });
+
+    let snapshot = handler.snapshot(&source.now());
+    assert_eq!(snapshot.flows.len(), 2);
+    assert_eq!(snapshot.hosts.len(), 3);
+
+    let dns: IpAddr = Ipv4Addr::new(10, 0, 0, 2).into();
+    let flow = snapshot.flows.iter().find(|flow| flow.daddr == dns).unwrap();
+    assert_eq!((flow.sport, flow.dport), (1000, 53));
+    assert_eq!((flow.stats.src2dst_pkts, flow.stats.dst2src_pkts), (1, 1));
+    assert_eq!((flow.stats.src2dst_bytes, flow.stats.dst2src_bytes), (52, 72));
+
+    // the first flow is idle since 1000s, the second one was seen at 1100s
+    let records = handler.purge_idle(&source.now());
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].tuple.daddr, dns);
+    assert_eq!((records[0].src2dst_pkts, records[0].dst2src_pkts), (1, 1));
+
+    let snapshot = handler.snapshot(&source.now());
+    assert_eq!(snapshot.flows.len(), 1);
+    assert_eq!(snapshot.flows[0].dport, 53);
+    assert!(snapshot.hosts.iter().all(|host| host.ip != dns));
+  }
+}
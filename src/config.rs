@@ -0,0 +1,337 @@
+use clap::{App, Arg, ArgMatches};
+use log::LevelFilter;
+use std::str::FromStr;
+
+use export::JsonFormat;
+use engine::types::IpNetwork;
+use engine::aggregate::{AggregationMode, load_network_names};
+
+const DEFAULT_SNAPLEN: i32 = 0;
+const DEFAULT_REDRAW_TIMEOUT_SEC: u64 = 3;
+const DEFAULT_STATS_TIMEOUT_SEC: u64 = 5;
+const DEFAULT_FLOW_IDLE_TIMEOUT_SEC: i64 = 60;
+const DEFAULT_HOST_IDLE_TIMEOUT_SEC: i64 = 300;
+const DEFAULT_REPLAY_SPEED: f64 = 1.;
+const DEFAULT_TOP_N: usize = 10;
+const DEFAULT_IPFIX_ACTIVE_TIMEOUT_SEC: i64 = 120;
+const MAX_THREADS: u64 = 64;
+const DEFAULT_IPV4_PREFIX: u8 = 24;
+const DEFAULT_IPV6_PREFIX: u8 = 64;
+
+#[derive(Clone)]
+pub struct Config {
+  pub interface: Option<String>,
+  pub read_file: Option<String>,
+  pub filter: Option<String>,
+  pub speed: f64,
+  pub top_n: usize,
+  pub list_interfaces: bool,
+  pub promisc: bool,
+  pub snaplen: i32,
+  pub redraw_timeout: u64,
+  pub stats_timeout: u64,
+  pub flow_idle_timeout: i64,
+  pub host_idle_timeout: i64,
+  pub log_level: LevelFilter,
+  pub threads: usize,
+  pub decap: bool,
+  pub batch: bool,
+  // batch mode limits, 0 for none
+  pub iterations: u64,
+  pub duration: u64,
+  // "-" for stdout
+  pub json_output: Option<String>,
+  pub json_format: JsonFormat,
+  pub json_interval: u64,
+  // host:port
+  pub ipfix_collector: Option<String>,
+  pub ipfix_active_timeout: i64,
+  // address:port of the Prometheus endpoint
+  pub metrics_address: Option<String>,
+  pub aggregation: AggregationMode,
+  pub ipv4_prefix: u8,
+  pub ipv6_prefix: u8,
+  // prefix to name table of the name aggregation
+  pub network_names: Vec<(IpNetwork, String)>,
+  // in addition to the ones of the capture interface
+  pub local_networks: Vec<IpNetwork>,
+}
+
+fn parse_value<T: FromStr>(matches: &ArgMatches, name: &str, default: T) -> T {
+  match matches.value_of(name) {
+    Some(val) => val.parse().unwrap_or_else(|_| {
+      eprintln!("Invalid value for --{}: {}", name, val);
+      ::std::process::exit(1);
+    }),
+    None => default,
+  }
+}
+
+fn is_number(val: String) -> Result<(), String> {
+  match val.parse::<u64>() {
+    Ok(_) => Ok(()),
+    Err(_) => Err(format!("'{}' is not a valid number", val)),
+  }
+}
+
+fn is_positive(val: String) -> Result<(), String> {
+  match val.parse::<u64>() {
+    Ok(n) if n > 0 => Ok(()),
+    _ => Err(format!("'{}' is not a positive number", val)),
+  }
+}
+
+fn is_ipv4_prefix(val: String) -> Result<(), String> {
+  match val.parse::<u8>() {
+    Ok(n) if n <= 32 => Ok(()),
+    _ => Err(format!("'{}' is not a valid IPv4 prefix length", val)),
+  }
+}
+
+fn is_ipv6_prefix(val: String) -> Result<(), String> {
+  match val.parse::<u8>() {
+    Ok(n) if n <= 128 => Ok(()),
+    _ => Err(format!("'{}' is not a valid IPv6 prefix length", val)),
+  }
+}
+
+fn is_network(val: String) -> Result<(), String> {
+  val.parse::<IpNetwork>().map(|_| ())
+}
+
+fn is_threads(val: String) -> Result<(), String> {
+  match val.parse::<u64>() {
+    Ok(n) if (n > 0) && (n <= MAX_THREADS) => Ok(()),
+    _ => Err(format!("'{}' is not a number between 1 and {}", val, MAX_THREADS)),
+  }
+}
+
+fn is_speed(val: String) -> Result<(), String> {
+  match val.parse::<f64>() {
+    Ok(n) if n >= 0. => Ok(()),
+    _ => Err(format!("'{}' is not a valid speed", val)),
+  }
+}
+
+impl Config {
+  pub fn from_args() -> Config {
+    let matches = App::new("top_hosts")
+      .about("Realtime view of the network connections of a given nic")
+      .arg(Arg::with_name("interface")
+        .short("i")
+        .long("interface")
+        .value_name("IFACE")
+        .help("Capture interface (default: the first available device)"))
+      .arg(Arg::with_name("read")
+        .short("f")
+        .long("read")
+        .value_name("FILE")
+        .conflicts_with("interface")
+        .help("Read packets from a pcap/pcapng file instead of capturing"))
+      .arg(Arg::with_name("filter")
+        .short("F")
+        .long("filter")
+        .value_name("EXPR")
+        .help("BPF capture filter, e.g. \"net 10.0.0.0/8 and not port 22\""))
+      .arg(Arg::with_name("speed")
+        .long("speed")
+        .value_name("N")
+        .requires("read")
+        .validator(is_speed)
+        .help("Replay the file at N times its original speed, 0 to read it as fast as possible \
+          and print a report of the top hosts and flows (default: 1)"))
+      .arg(Arg::with_name("top")
+        .short("t")
+        .long("top")
+        .value_name("N")
+        .validator(is_positive)
+        .help("Number of hosts and flows shown in reports (default: 10)"))
+      .arg(Arg::with_name("list-interfaces")
+        .short("L")
+        .long("list-interfaces")
+        .help("List the available capture interfaces and exit"))
+      .arg(Arg::with_name("no-promisc")
+        .short("n")
+        .long("no-promisc")
+        .help("Do not put the interface into promiscuous mode"))
+      .arg(Arg::with_name("snaplen")
+        .short("s")
+        .long("snaplen")
+        .value_name("BYTES")
+        .validator(is_number)
+        .help("Maximum number of bytes captured per packet (0: libpcap default)"))
+      .arg(Arg::with_name("refresh")
+        .short("r")
+        .long("refresh")
+        .value_name("SEC")
+        .validator(is_positive)
+        .help("Screen refresh interval, or report interval in batch mode"))
+      .arg(Arg::with_name("stats-interval")
+        .long("stats-interval")
+        .value_name("SEC")
+        .validator(is_positive)
+        .help("Throughput calculation interval"))
+      .arg(Arg::with_name("flow-timeout")
+        .long("flow-timeout")
+        .value_name("SEC")
+        .validator(is_positive)
+        .help("Idle time after which a flow is purged"))
+      .arg(Arg::with_name("host-timeout")
+        .long("host-timeout")
+        .value_name("SEC")
+        .validator(is_positive)
+        .help("Idle time after which a host without flows is purged"))
+      .arg(Arg::with_name("threads")
+        .short("T")
+        .long("threads")
+        .value_name("N")
+        .validator(is_threads)
+        .help("Number of packet processing threads, up to 64 (default: 1)"))
+      .arg(Arg::with_name("decap")
+        .short("d")
+        .long("decap")
+        .help("Account the inner packets of the GRE, VXLAN, GTP-U and IP-in-IP tunnels"))
+      .arg(Arg::with_name("batch")
+        .short("b")
+        .long("batch")
+        .help("Print a report of the top hosts and flows every refresh interval instead of \
+          running the interactive display"))
+      .arg(Arg::with_name("iterations")
+        .long("iterations")
+        .value_name("N")
+        .requires("batch")
+        .validator(is_positive)
+        .help("Exit after N reports"))
+      .arg(Arg::with_name("duration")
+        .long("duration")
+        .value_name("SEC")
+        .requires("batch")
+        .validator(is_positive)
+        .help("Exit after SEC seconds, printing a final report"))
+      .arg(Arg::with_name("json")
+        .short("j")
+        .long("json")
+        .value_name("FILE")
+        .help("Export the flows and hosts as JSON to FILE (appended), - for stdout, which replaces the \
+          batch reports"))
+      .arg(Arg::with_name("json-format")
+        .long("json-format")
+        .value_name("FORMAT")
+        .requires("json")
+        .possible_values(&["ndjson", "json"])
+        .help("ndjson: a line per flow and host, json: a document per export (default: ndjson)"))
+      .arg(Arg::with_name("json-interval")
+        .long("json-interval")
+        .value_name("SEC")
+        .requires("json")
+        .validator(is_number)
+        .help("Export interval, 0 to only export on exit (default: 0)"))
+      .arg(Arg::with_name("ipfix")
+        .long("ipfix")
+        .value_name("HOST:PORT")
+        .help("Export the flows as IPFIX to the collector, when they expire or on active timeout"))
+      .arg(Arg::with_name("ipfix-active-timeout")
+        .long("ipfix-active-timeout")
+        .value_name("SEC")
+        .requires("ipfix")
+        .validator(is_positive)
+        .help("Export interval of the active flows (default: 120)"))
+      .arg(Arg::with_name("metrics")
+        .long("metrics")
+        .value_name("ADDR:PORT")
+        .help("Serve Prometheus metrics at http://ADDR:PORT/metrics, e.g. 127.0.0.1:9100"))
+      .arg(Arg::with_name("aggregate")
+        .short("a")
+        .long("aggregate")
+        .value_name("MODE")
+        .possible_values(&["host", "network", "name", "mac"])
+        .help("Group the hosts by IP address, network prefix, name from the --networks table \
+          or MAC address (default: host)"))
+      .arg(Arg::with_name("prefix-v4")
+        .long("prefix-v4")
+        .value_name("LEN")
+        .validator(is_ipv4_prefix)
+        .help("IPv4 prefix length of the network aggregation (default: 24)"))
+      .arg(Arg::with_name("prefix-v6")
+        .long("prefix-v6")
+        .value_name("LEN")
+        .validator(is_ipv6_prefix)
+        .help("IPv6 prefix length of the network aggregation (default: 64)"))
+      .arg(Arg::with_name("networks")
+        .long("networks")
+        .value_name("FILE")
+        .help("Network names table, one \"CIDR name\" per line, e.g. \"10.1.0.0/16 office\""))
+      .arg(Arg::with_name("local-net")
+        .long("local-net")
+        .value_name("CIDR")
+        .multiple(true)
+        .number_of_values(1)
+        .validator(is_network)
+        .help("A local network, in addition to the ones of the capture interface addresses. \
+          Can be repeated"))
+      .arg(Arg::with_name("log-level")
+        .short("l")
+        .long("log-level")
+        .value_name("LEVEL")
+        .possible_values(&["off", "error", "warn", "info", "debug", "trace"])
+        .help("Logging verbosity (default: info)"))
+      .get_matches();
+
+    let network_names = match matches.value_of("networks") {
+      Some(path) => load_network_names(path).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        ::std::process::exit(1);
+      }),
+      None => Vec::new(),
+    };
+
+    let aggregation = match matches.value_of("aggregate") {
+      Some("network") => AggregationMode::Network,
+      Some("name") => AggregationMode::Name,
+      Some("mac") => AggregationMode::Mac,
+      _ => AggregationMode::Host,
+    };
+
+    if (aggregation == AggregationMode::Name) && network_names.is_empty() {
+      eprintln!("--aggregate name requires a --networks table");
+      ::std::process::exit(1);
+    }
+
+    Config {
+      interface: matches.value_of("interface").map(|s| s.to_string()),
+      read_file: matches.value_of("read").map(|s| s.to_string()),
+      filter: matches.value_of("filter").map(|s| s.to_string()),
+      speed: parse_value(&matches, "speed", DEFAULT_REPLAY_SPEED),
+      top_n: parse_value(&matches, "top", DEFAULT_TOP_N),
+      list_interfaces: matches.is_present("list-interfaces"),
+      promisc: !matches.is_present("no-promisc"),
+      snaplen: parse_value(&matches, "snaplen", DEFAULT_SNAPLEN),
+      redraw_timeout: parse_value(&matches, "refresh", DEFAULT_REDRAW_TIMEOUT_SEC),
+      stats_timeout: parse_value(&matches, "stats-interval", DEFAULT_STATS_TIMEOUT_SEC),
+      flow_idle_timeout: parse_value(&matches, "flow-timeout", DEFAULT_FLOW_IDLE_TIMEOUT_SEC),
+      host_idle_timeout: parse_value(&matches, "host-timeout", DEFAULT_HOST_IDLE_TIMEOUT_SEC),
+      log_level: parse_value(&matches, "log-level", LevelFilter::Info),
+      threads: parse_value(&matches, "threads", 1),
+      decap: matches.is_present("decap"),
+      batch: matches.is_present("batch"),
+      iterations: parse_value(&matches, "iterations", 0),
+      duration: parse_value(&matches, "duration", 0),
+      json_output: matches.value_of("json").map(|s| s.to_string()),
+      json_format: match matches.value_of("json-format") {
+        Some("json") => JsonFormat::Json,
+        _ => JsonFormat::Ndjson,
+      },
+      json_interval: parse_value(&matches, "json-interval", 0),
+      ipfix_collector: matches.value_of("ipfix").map(|s| s.to_string()),
+      ipfix_active_timeout: parse_value(&matches, "ipfix-active-timeout", DEFAULT_IPFIX_ACTIVE_TIMEOUT_SEC),
+      metrics_address: matches.value_of("metrics").map(|s| s.to_string()),
+      aggregation: aggregation,
+      ipv4_prefix: parse_value(&matches, "prefix-v4", DEFAULT_IPV4_PREFIX),
+      ipv6_prefix: parse_value(&matches, "prefix-v6", DEFAULT_IPV6_PREFIX),
+      network_names: network_names,
+      local_networks: matches.values_of("local-net")
+        .map(|values| values.map(|val| val.parse().unwrap()).collect())
+        .unwrap_or_default(),
+    }
+  }
+}
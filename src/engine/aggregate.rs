@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use super::types::*;
+use super::snapshot::{FlowInfo, HostInfo, HostGroup};
+
+// The group of the hosts not in the names table
+const OTHER_GROUP: &str = "other";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AggregationMode {
+  // no aggregation
+  Host,
+  // by CIDR prefix
+  Network,
+  // by the names table
+  Name,
+  Mac,
+}
+
+// Groups the hosts according to the mode
+#[derive(Clone)]
+pub struct Aggregator {
+  pub mode: AggregationMode,
+  pub ipv4_prefix: u8,
+  pub ipv6_prefix: u8,
+  names: Vec<(IpNetwork, String)>,
+}
+
+/* one "network name" pair per line, e.g. "10.1.0.0/16 office", # starts a comment */
+pub fn load_network_names(path: &str) -> Result<Vec<(IpNetwork, String)>, String> {
+  let file = File::open(path).map_err(|err| format!("{}: {}", path, err))?;
+  let mut names = Vec::new();
+
+  for (i, line) in BufReader::new(file).lines().enumerate() {
+    let line = line.map_err(|err| format!("{}: {}", path, err))?;
+    let line = line.split('#').next().unwrap().trim();
+
+    if line.is_empty() {
+      continue;
+    }
+
+    let mut parts = line.splitn(2, char::is_whitespace);
+    let network = parts.next().unwrap().parse::<IpNetwork>()
+      .map_err(|err| format!("{}:{}: {}", path, i + 1, err))?;
+    let name = parts.next().map(|name| name.trim()).filter(|name| !name.is_empty())
+      .ok_or(format!("{}:{}: missing name", path, i + 1))?;
+
+    names.push((network, name.to_string()));
+  }
+
+  Ok(names)
+}
+
+impl Aggregator {
+  pub fn new(mode: AggregationMode, ipv4_prefix: u8, ipv6_prefix: u8, names: Vec<(IpNetwork, String)>) -> Aggregator {
+    Aggregator {
+      mode: mode,
+      ipv4_prefix: ipv4_prefix,
+      ipv6_prefix: ipv6_prefix,
+      names: names,
+    }
+  }
+
+  pub fn has_names(&self) -> bool {
+    !self.names.is_empty()
+  }
+
+  /* the next mode, to cycle through them. Name is skipped without a names table */
+  pub fn next_mode(&mut self) {
+    self.mode = match self.mode {
+      AggregationMode::Host => AggregationMode::Network,
+      AggregationMode::Network if self.has_names() => AggregationMode::Name,
+      AggregationMode::Network | AggregationMode::Name => AggregationMode::Mac,
+      AggregationMode::Mac => AggregationMode::Host,
+    };
+  }
+
+  pub fn mode_name(&self) -> String {
+    match self.mode {
+      AggregationMode::Host => "host".to_string(),
+      AggregationMode::Network => format!("network /{} /{}", self.ipv4_prefix, self.ipv6_prefix),
+      AggregationMode::Name => "name".to_string(),
+      AggregationMode::Mac => "MAC".to_string(),
+    }
+  }
+
+  /* the longest prefix match */
+  fn network_name(&self, ip: &IpAddr) -> &str {
+    self.names.iter()
+      .filter(|&&(ref network, _)| network.contains(ip))
+      .max_by_key(|&&(ref network, _)| network.prefix)
+      .map(|&(_, ref name)| name.as_str())
+      .unwrap_or(OTHER_GROUP)
+  }
+
+  fn group_name(&self, host: &HostInfo) -> String {
+    match self.mode {
+      AggregationMode::Host => host.ip.to_string(),
+      AggregationMode::Network => {
+        let prefix = if host.ip.is_ipv4() { self.ipv4_prefix } else { self.ipv6_prefix };
+        IpNetwork::from_addr(&host.ip, prefix).to_string()
+      },
+      AggregationMode::Name => self.network_name(&host.ip).to_string(),
+      AggregationMode::Mac => host.mac.to_hex_string(),
+    }
+  }
+
+  /* the traffic between two hosts of the same group is both sent and received by
+   * the group, but their flows are only counted once. flows are the ones of the hosts */
+  pub fn aggregate<'a, I>(&self, hosts: I, flows: &[FlowInfo]) -> Vec<HostGroup>
+      where I: Iterator<Item=&'a HostInfo> {
+    let mut groups: HashMap<String, HostGroup> = HashMap::new();
+    let mut host_groups: HashMap<HostKey, String> = HashMap::new();
+
+    for host in hosts {
+      let name = self.group_name(host);
+      let group = groups.entry(name.clone()).or_insert_with(|| HostGroup {
+        name: name.clone(),
+        num_hosts: 0,
+        num_flows: 0,
+        stats: Default::default(),
+      });
+
+      group.num_hosts += 1;
+      group.stats.merge(&host.stats);
+      host_groups.insert(host.key(), name);
+    }
+
+    for flow in flows {
+      let src_group = host_groups.get(&flow.key.src_host());
+      let dst_group = host_groups.get(&flow.key.dst_host());
+
+      if let Some(name) = src_group {
+        groups.get_mut(name).unwrap().num_flows += 1;
+      }
+
+      if let Some(name) = dst_group {
+        if src_group != Some(name) {
+          groups.get_mut(name).unwrap().num_flows += 1;
+        }
+      }
+    }
+
+    groups.into_iter().map(|(_, group)| group).collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+  use std::fs;
+  use std::io::Write;
+  use super::*;
+  use super::super::traffic_stats::TrafficStats;
+
+  /* writes contents to a temporary file and parses it */
+  fn load(name: &str, contents: &str) -> Result<Vec<(IpNetwork, String)>, String> {
+    let path = env::temp_dir().join(format!("top_hosts_{}_{}", name, ::std::process::id()));
+    File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+
+    let result = load_network_names(path.to_str().unwrap());
+    fs::remove_file(&path).unwrap();
+    result
+  }
+
+  #[test]
+  fn network_names() {
+    let names = load("names", "# networks\n\n10.1.0.0/16 office\n  fe80::/10\tlink local  # comment\n10.0.0.1 gateway\n").unwrap();
+
+    assert_eq!(names, vec![
+      ("10.1.0.0/16".parse().unwrap(), "office".to_string()),
+      ("fe80::/10".parse().unwrap(), "link local".to_string()),
+      ("10.0.0.1/32".parse().unwrap(), "gateway".to_string()),
+    ]);
+  }
+
+  #[test]
+  fn network_names_errors() {
+    let err = load("missing_name", "10.1.0.0/16 office\n10.2.0.0/16\n").unwrap_err();
+    assert!(err.ends_with(":2: missing name"), "{}", err);
+
+    assert!(load("invalid_network", "10.1.0.0/33 office\n").is_err());
+    assert!(load_network_names("/nonexistent/top_hosts_networks").is_err());
+  }
+
+  /* sent and received bytes */
+  fn host(ip: &str, sent: u64, received: u64) -> HostInfo {
+    HostInfo {
+      ip: ip.parse().unwrap(),
+      vlan: 0,
+      mac: MacAddress::nil(),
+      local: false,
+      stats: TrafficStats { src2dst_bytes: sent, dst2src_bytes: received, ..Default::default() },
+      num_flows: 1,
+    }
+  }
+
+  fn flow(saddr: &str, daddr: &str) -> FlowInfo {
+    let tuple = PacketTuple { vlan: 0, proto: IPPROTO_UDP, saddr: saddr.parse().unwrap(), daddr: daddr.parse().unwrap(), sport: 1000, dport: 53 };
+
+    FlowInfo {
+      key: tuple.key(),
+      vlan: 0,
+      l4proto: L4Proto::UDP,
+      proto: tuple.proto,
+      saddr: tuple.saddr,
+      smac: MacAddress::nil(),
+      daddr: tuple.daddr,
+      dmac: MacAddress::nil(),
+      src_local: false,
+      dst_local: false,
+      sport: tuple.sport,
+      dport: tuple.dport,
+      stats: TrafficStats::default(),
+      protocol: "DNS".into(),
+      master_protocol: "Unknown".into(),
+      app_protocol: "DNS".into(),
+      detection_completed: true,
+      tunnel: None,
+      icmp_type_code: (0, 0),
+    }
+  }
+
+  fn group<'a>(groups: &'a [HostGroup], name: &str) -> &'a HostGroup {
+    groups.iter().find(|group| group.name == name).unwrap()
+  }
+
+  #[test]
+  fn flows_within_a_group() {
+    let aggregator = Aggregator::new(AggregationMode::Network, 24, 64, Vec::new());
+    let hosts = vec![host("10.0.0.1", 100, 0), host("10.0.0.2", 0, 100)];
+
+    let groups = aggregator.aggregate(hosts.iter(), &[flow("10.0.0.1", "10.0.0.2")]);
+    assert_eq!(groups.len(), 1);
+    assert_eq!(groups[0].name, "10.0.0.0/24");
+    assert_eq!(groups[0].num_hosts, 2);
+    assert_eq!(groups[0].num_flows, 1);
+    // both sent and received by the group
+    assert_eq!((groups[0].stats.src2dst_bytes, groups[0].stats.dst2src_bytes), (100, 100));
+
+    // a flow between groups counts in both
+    let mut hosts = hosts;
+    hosts.push(host("192.0.2.1", 0, 0));
+    let groups = aggregator.aggregate(hosts.iter(), &[flow("10.0.0.1", "10.0.0.2"), flow("192.0.2.1", "10.0.0.2")]);
+    assert_eq!(groups.len(), 2);
+    assert_eq!(group(&groups, "10.0.0.0/24").num_flows, 2);
+    assert_eq!(group(&groups, "192.0.2.0/24").num_flows, 1);
+  }
+}
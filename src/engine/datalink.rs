@@ -0,0 +1,383 @@
+use super::types::*;
+
+// pcap link types, see pcap-linktype(7)
+pub const DLT_NULL: i32 = 0;
+pub const DLT_EN10MB: i32 = 1;
+pub const DLT_RAW: i32 = 12;
+// DLT_RAW on OpenBSD
+pub const DLT_RAW_OPENBSD: i32 = 14;
+pub const LINKTYPE_RAW: i32 = 101;
+pub const DLT_IEEE802_11: i32 = 105;
+// like DLT_NULL, but the family is in network byte order
+pub const DLT_LOOP: i32 = 108;
+pub const DLT_LINUX_SLL: i32 = 113;
+pub const DLT_IEEE802_11_RADIOTAP: i32 = 127;
+pub const DLT_IPV4: i32 = 228;
+pub const DLT_IPV6: i32 = 229;
+pub const DLT_LINUX_SLL2: i32 = 276;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+const ETHERTYPE_QINQ_OLD: u16 = 0x9100;
+const ETHERTYPE_MPLS_UNICAST: u16 = 0x8847;
+const ETHERTYPE_MPLS_MULTICAST: u16 = 0x8848;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const SLL_HEADER_LEN: usize = 16;
+const SLL2_HEADER_LEN: usize = 20;
+const NULL_HEADER_LEN: usize = 4;
+const VLAN_TAG_LEN: usize = 4;
+const MPLS_LABEL_LEN: usize = 4;
+const IEEE802_11_HEADER_LEN: usize = 24;
+const IEEE802_11_QOS_CONTROL_LEN: usize = 2;
+const IEEE802_11_HT_CONTROL_LEN: usize = 4;
+const LLC_SNAP_HEADER_LEN: usize = 8;
+
+// The IP packet within a link layer frame
+pub struct LinkInfo<'a> {
+  pub srcmac: MacAddress,
+  pub dstmac: MacAddress,
+  // the outermost 802.1Q VLAN ID, 0 if untagged
+  pub vlan: u16,
+  pub ip: &'a [u8],
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+  ((data[offset] as u16) << 8) | (data[offset + 1] as u16)
+}
+
+fn read_mac(data: &[u8], offset: usize) -> MacAddress {
+  let mut mac = [0u8; 6];
+
+  mac.copy_from_slice(&data[offset..offset + 6]);
+  MacAddress::new(mac)
+}
+
+/* when there is no ethertype, the IP version is in the first nibble */
+pub fn ip_by_version(data: &[u8]) -> Option<&[u8]> {
+  match data.first().map(|byte| byte >> 4) {
+    Some(4) | Some(6) => Some(data),
+    _ => None,
+  }
+}
+
+/* strips the VLAN tags and MPLS labels, sets the VLAN ID */
+fn parse_ethertype<'a>(mut ethertype: u16, mut payload: &'a [u8], vlan: &mut u16) -> Option<&'a [u8]> {
+  loop {
+    match ethertype {
+      ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => return Some(payload),
+      ETHERTYPE_VLAN | ETHERTYPE_QINQ | ETHERTYPE_QINQ_OLD => {
+        if payload.len() < VLAN_TAG_LEN {
+          return None;
+        }
+
+        if *vlan == 0 {
+          *vlan = read_u16(payload, 0) & 0x0fff;
+        }
+
+        ethertype = read_u16(payload, 2);
+        payload = &payload[VLAN_TAG_LEN..];
+      },
+      ETHERTYPE_MPLS_UNICAST | ETHERTYPE_MPLS_MULTICAST => {
+        loop {
+          if payload.len() < MPLS_LABEL_LEN {
+            return None;
+          }
+
+          let bottom_of_stack = (payload[2] & 0x01) != 0;
+          payload = &payload[MPLS_LABEL_LEN..];
+
+          if bottom_of_stack {
+            // an Ethernet pseudowire is not supported
+            return ip_by_version(payload);
+          }
+        }
+      },
+      _ => return None,
+    }
+  }
+}
+
+/* data frames only, encrypted frames cannot be parsed */
+fn parse_ieee802_11<'a>(frame: &'a [u8], info: &mut LinkInfo<'a>) -> Option<&'a [u8]> {
+  if frame.len() < IEEE802_11_HEADER_LEN {
+    return None;
+  }
+
+  let (fc0, fc1) = (frame[0], frame[1]);
+  let is_data = (fc0 & 0x0c) == 0x08;
+  let is_qos = (fc0 & 0x80) != 0;
+  let is_protected = (fc1 & 0x40) != 0;
+  // on QoS data frames, the Order bit signals an HT Control field
+  let has_ht_control = is_qos && ((fc1 & 0x80) != 0);
+  let (to_ds, from_ds) = ((fc1 & 0x01) != 0, (fc1 & 0x02) != 0);
+
+  if !is_data || is_protected {
+    return None;
+  }
+
+  let mut header_len = IEEE802_11_HEADER_LEN;
+
+  if to_ds && from_ds {
+    header_len += 6;
+  }
+
+  if is_qos {
+    header_len += IEEE802_11_QOS_CONTROL_LEN;
+  }
+
+  if has_ht_control {
+    header_len += IEEE802_11_HT_CONTROL_LEN;
+  }
+
+  if frame.len() < header_len + LLC_SNAP_HEADER_LEN {
+    return None;
+  }
+
+  let (dst_offset, src_offset) = match (to_ds, from_ds) {
+    (false, false) => (4, 10),
+    (true, false) => (16, 10),
+    (false, true) => (4, 16),
+    (true, true) => (16, 24),
+  };
+
+  info.dstmac = read_mac(frame, dst_offset);
+  info.srcmac = read_mac(frame, src_offset);
+
+  let llc = &frame[header_len..];
+
+  // only LLC SNAP with an ethertype
+  if (llc[0] != 0xaa) || (llc[1] != 0xaa) || (llc[2] != 0x03) {
+    return None;
+  }
+
+  parse_ethertype(read_u16(llc, 6), &llc[LLC_SNAP_HEADER_LEN..], &mut info.vlan)
+}
+
+/* returns None for the unsupported link types and for the non IP frames */
+pub fn decapsulate(linktype: i32, frame: &[u8]) -> Option<LinkInfo> {
+  let mut info = LinkInfo {
+    srcmac: MacAddress::nil(),
+    dstmac: MacAddress::nil(),
+    vlan: 0,
+    ip: &[],
+  };
+
+  let ip = match linktype {
+    DLT_EN10MB => {
+      if frame.len() < ETHERNET_HEADER_LEN {
+        return None;
+      }
+
+      info.dstmac = read_mac(frame, 0);
+      info.srcmac = read_mac(frame, 6);
+      parse_ethertype(read_u16(frame, 12), &frame[ETHERNET_HEADER_LEN..], &mut info.vlan)
+    },
+    DLT_LINUX_SLL => {
+      if frame.len() < SLL_HEADER_LEN {
+        return None;
+      }
+
+      // the link layer address of the sender
+      if read_u16(frame, 4) == 6 {
+        info.srcmac = read_mac(frame, 6);
+      }
+
+      parse_ethertype(read_u16(frame, 14), &frame[SLL_HEADER_LEN..], &mut info.vlan)
+    },
+    DLT_LINUX_SLL2 => {
+      if frame.len() < SLL2_HEADER_LEN {
+        return None;
+      }
+
+      if frame[11] == 6 {
+        info.srcmac = read_mac(frame, 12);
+      }
+
+      parse_ethertype(read_u16(frame, 0), &frame[SLL2_HEADER_LEN..], &mut info.vlan)
+    },
+    DLT_NULL | DLT_LOOP => {
+      // the address family values differ between the OSes
+      if frame.len() < NULL_HEADER_LEN {
+        return None;
+      }
+
+      ip_by_version(&frame[NULL_HEADER_LEN..])
+    },
+    DLT_RAW | DLT_RAW_OPENBSD | LINKTYPE_RAW | DLT_IPV4 | DLT_IPV6 => ip_by_version(frame),
+    DLT_IEEE802_11 => parse_ieee802_11(frame, &mut info),
+    DLT_IEEE802_11_RADIOTAP => {
+      if frame.len() < 4 {
+        return None;
+      }
+
+      // little endian
+      let radiotap_len = (frame[2] as usize) | ((frame[3] as usize) << 8);
+
+      if frame.len() < radiotap_len {
+        return None;
+      }
+
+      parse_ieee802_11(&frame[radiotap_len..], &mut info)
+    },
+    _ => None,
+  };
+
+  ip.map(|ip| LinkInfo { ip: ip, ..info })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SRCMAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+  const DSTMAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x02];
+
+  /* an IPv4 UDP packet, 10.0.0.1:1000 -> 10.0.0.2:53 */
+  fn ipv4_packet() -> Vec<u8> {
+    vec![
+      0x45, 0, 0, 32, 0, 0, 0x40, 0, 64, IPPROTO_UDP, 0, 0,
+      10, 0, 0, 1, 10, 0, 0, 2,
+      0x03, 0xe8, 0, 53, 0, 12, 0, 0, 1, 2, 3, 4,
+    ]
+  }
+
+  /* the header of an IPv6 packet without payload */
+  fn ipv6_packet() -> Vec<u8> {
+    let mut packet = vec![0x60, 0, 0, 0, 0, 0, 59, 64];
+
+    packet.extend_from_slice(&Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).octets());
+    packet.extend_from_slice(&Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2).octets());
+    packet
+  }
+
+  /* the MAC addresses, then the ethertype with its tags and labels */
+  fn ethernet(ethertype: &[u8]) -> Vec<u8> {
+    let mut frame = DSTMAC.to_vec();
+
+    frame.extend_from_slice(&SRCMAC);
+    frame.extend_from_slice(ethertype);
+    frame.extend_from_slice(&ipv4_packet());
+    frame
+  }
+
+  /* a QoS data frame from the distribution system, with an LLC SNAP header */
+  fn ieee802_11_qos(ht_control: bool) -> Vec<u8> {
+    let mut frame = vec![0x88, if ht_control { 0x82 } else { 0x02 }, 0, 0];
+
+    frame.extend_from_slice(&DSTMAC);
+    // the BSSID, then the source
+    frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 0xff]);
+    frame.extend_from_slice(&SRCMAC);
+    frame.extend_from_slice(&[0, 0, 0x07, 0]);
+    if ht_control {
+      frame.extend_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+    }
+    frame.extend_from_slice(&[0xaa, 0xaa, 0x03, 0, 0, 0, 0x08, 0x00]);
+    frame.extend_from_slice(&ipv4_packet());
+    frame
+  }
+
+  /* checks the VLAN ID and that the IPv4 packet is found */
+  fn assert_ipv4(linktype: i32, frame: &[u8], vlan: u16) -> LinkInfo {
+    let info = decapsulate(linktype, frame).unwrap();
+
+    assert_eq!(info.vlan, vlan);
+    assert_eq!(info.ip, &ipv4_packet()[..]);
+    info
+  }
+
+  #[test]
+  fn ethernet_vlans() {
+    let info = assert_ipv4(DLT_EN10MB, &ethernet(&[0x08, 0x00]), 0);
+    assert_eq!(info.srcmac, MacAddress::new(SRCMAC));
+    assert_eq!(info.dstmac, MacAddress::new(DSTMAC));
+
+    // the priority bits are not part of the ID
+    assert_ipv4(DLT_EN10MB, &ethernet(&[0x81, 0x00, 0x20, 0x0a, 0x08, 0x00]), 10);
+
+    // QinQ: the outer one
+    assert_ipv4(DLT_EN10MB, &ethernet(&[0x88, 0xa8, 0x00, 0x64, 0x81, 0x00, 0x00, 0x0a, 0x08, 0x00]), 100);
+    assert_ipv4(DLT_EN10MB, &ethernet(&[0x91, 0x00, 0x00, 0x64, 0x81, 0x00, 0x00, 0x0a, 0x08, 0x00]), 100);
+
+    // ARP
+    assert!(decapsulate(DLT_EN10MB, &ethernet(&[0x08, 0x06])).is_none());
+    assert!(decapsulate(DLT_EN10MB, &ethernet(&[0x81, 0x00])[..16]).is_none());
+  }
+
+  #[test]
+  fn mpls_labels() {
+    // the bottom of stack bit ends the labels
+    assert_ipv4(DLT_EN10MB, &ethernet(&[0x88, 0x47, 0x00, 0x01, 0x00, 0x40, 0x00, 0x02, 0x01, 0x40]), 0);
+    assert_ipv4(DLT_EN10MB, &ethernet(&[0x81, 0x00, 0x00, 0x05, 0x88, 0x48, 0x00, 0x02, 0x01, 0x40]), 5);
+
+    // truncated before the bottom of stack
+    let frame = ethernet(&[0x88, 0x47]);
+    let mut labels = frame[..ETHERNET_HEADER_LEN].to_vec();
+    labels.extend_from_slice(&[0x00, 0x01, 0x00, 0x40, 0x00, 0x02]);
+    assert!(decapsulate(DLT_EN10MB, &labels).is_none());
+  }
+
+  #[test]
+  fn linux_cooked() {
+    // packet type, ARPHRD_ETHER, address length, address, ethertype
+    let mut frame = vec![0, 0, 0, 1, 0, 6];
+    frame.extend_from_slice(&SRCMAC);
+    frame.extend_from_slice(&[0, 0, 0x81, 0x00, 0x00, 0x0a, 0x08, 0x00]);
+    frame.extend_from_slice(&ipv4_packet());
+
+    let info = assert_ipv4(DLT_LINUX_SLL, &frame, 10);
+    assert_eq!(info.srcmac, MacAddress::new(SRCMAC));
+    assert!(info.dstmac.is_nil());
+
+    // ethertype, reserved, interface index, ARPHRD_ETHER, packet type, address length, address
+    let mut frame = vec![0x08, 0x00, 0, 0, 0, 0, 0, 2, 0, 1, 0, 6];
+    frame.extend_from_slice(&SRCMAC);
+    frame.extend_from_slice(&[0, 0]);
+    frame.extend_from_slice(&ipv4_packet());
+
+    let info = assert_ipv4(DLT_LINUX_SLL2, &frame, 0);
+    assert_eq!(info.srcmac, MacAddress::new(SRCMAC));
+  }
+
+  #[test]
+  fn loopback_and_raw() {
+    let mut null = vec![2, 0, 0, 0];
+    null.extend_from_slice(&ipv4_packet());
+    assert_ipv4(DLT_NULL, &null, 0);
+
+    let mut loopback = vec![0, 0, 0, 2];
+    loopback.extend_from_slice(&ipv4_packet());
+    assert_ipv4(DLT_LOOP, &loopback, 0);
+
+    for &linktype in &[DLT_RAW, DLT_RAW_OPENBSD, LINKTYPE_RAW, DLT_IPV4] {
+      assert_ipv4(linktype, &ipv4_packet(), 0);
+    }
+
+    let ipv6 = ipv6_packet();
+    assert_eq!(decapsulate(DLT_IPV6, &ipv6).unwrap().ip, &ipv6[..]);
+    assert!(decapsulate(DLT_RAW, &[0x00, 0x01]).is_none());
+  }
+
+  #[test]
+  fn ieee802_11() {
+    for &ht_control in &[false, true] {
+      let frame = ieee802_11_qos(ht_control);
+      let info = assert_ipv4(DLT_IEEE802_11, &frame, 0);
+      assert_eq!(info.srcmac, MacAddress::new(SRCMAC));
+      assert_eq!(info.dstmac, MacAddress::new(DSTMAC));
+
+      // a radiotap header of 8 bytes, without fields
+      let mut radiotap = vec![0, 0, 8, 0, 0, 0, 0, 0];
+      radiotap.extend_from_slice(&frame);
+      assert_ipv4(DLT_IEEE802_11_RADIOTAP, &radiotap, 0);
+    }
+
+    // protected
+    let mut frame = ieee802_11_qos(false);
+    frame[1] |= 0x40;
+    assert!(decapsulate(DLT_IEEE802_11, &frame).is_none());
+  }
+}
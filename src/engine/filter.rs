@@ -0,0 +1,206 @@
+use std::collections::HashSet;
+
+use super::types::*;
+use super::snapshot::{FlowInfo, HostInfo};
+
+// A single filter term, see FlowFilter::parse
+#[derive(Debug)]
+enum Term {
+  Network(IpNetwork),
+  Port(u16),
+  L4Proto(u8),
+  Vlan(u16),
+  // true for local, false for remote
+  Local(bool),
+  // lowercase, matched as substring of the nDPI protocol name
+  Protocol(String),
+}
+
+// A display filter made of space separated terms which must all match,
+// e.g. "proto:TLS port:443 10.0.0.0/8"
+#[derive(Debug, Default)]
+pub struct FlowFilter {
+  terms: Vec<Term>,
+}
+
+impl FlowFilter {
+  pub fn parse(filter: &str) -> Result<FlowFilter, String> {
+    let mut terms = Vec::new();
+
+    for token in filter.split_whitespace() {
+      let term = if token.starts_with("proto:") {
+        Term::Protocol(token["proto:".len()..].to_lowercase())
+      } else if token.starts_with("vlan:") {
+        let vlan = &token["vlan:".len()..];
+        Term::Vlan(vlan.parse().map_err(|_| format!("Invalid VLAN: {}", vlan))?)
+      } else if token.starts_with("port:") {
+        let port = &token["port:".len()..];
+        Term::Port(port.parse().map_err(|_| format!("Invalid port: {}", port))?)
+      } else {
+        match token.to_lowercase().as_str() {
+          "tcp" => Term::L4Proto(IPPROTO_TCP),
+          "udp" => Term::L4Proto(IPPROTO_UDP),
+          "icmp" => Term::L4Proto(IPPROTO_ICMP),
+          "icmp6" => Term::L4Proto(IPPROTO_ICMPV6),
+          "gre" => Term::L4Proto(IPPROTO_GRE),
+          "esp" => Term::L4Proto(IPPROTO_ESP),
+          "sctp" => Term::L4Proto(IPPROTO_SCTP),
+          "local" => Term::Local(true),
+          "remote" => Term::Local(false),
+          _ => Term::Network(token.parse()?),
+        }
+      };
+
+      terms.push(term);
+    }
+
+    Ok(FlowFilter { terms: terms })
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.terms.is_empty()
+  }
+
+  pub fn matches_flow(&self, flow: &FlowInfo) -> bool {
+    self.terms.iter().all(|term| {
+      match term {
+        Term::Network(net) => net.contains(&flow.saddr) || net.contains(&flow.daddr),
+        Term::Port(port) => has_ports(flow.proto) && ((flow.sport == *port) || (flow.dport == *port)),
+        Term::L4Proto(proto) => flow.proto == *proto,
+        Term::Vlan(vlan) => flow.vlan == *vlan,
+        Term::Local(local) => (flow.src_local == *local) || (flow.dst_local == *local),
+        Term::Protocol(name) => flow.protocol.to_lowercase().contains(name.as_str()),
+      }
+    })
+  }
+
+  /* flow terms cannot be checked on the host itself, so they are checked on
+   * the flows: matching_flows_hosts contains the hosts of the matching flows */
+  pub fn matches_host(&self, host: &HostInfo, matching_flows_hosts: &HashSet<HostKey>) -> bool {
+    self.terms.iter().all(|term| {
+      match term {
+        Term::Network(net) => net.contains(&host.ip),
+        Term::Vlan(vlan) => host.vlan == *vlan,
+        Term::Local(local) => host.local == *local,
+        _ => matching_flows_hosts.contains(&host.key()),
+      }
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::traffic_stats::TrafficStats;
+
+  fn flow(proto: u8, saddr: &str, sport: u16, daddr: &str, dport: u16, protocol: &str) -> FlowInfo {
+    let tuple = PacketTuple { vlan: 0, proto: proto, saddr: saddr.parse().unwrap(), daddr: daddr.parse().unwrap(), sport: sport, dport: dport };
+
+    FlowInfo {
+      key: tuple.key(),
+      vlan: 0,
+      l4proto: L4Proto::UNKNOWN,
+      proto: proto,
+      saddr: tuple.saddr,
+      smac: MacAddress::nil(),
+      daddr: tuple.daddr,
+      dmac: MacAddress::nil(),
+      src_local: false,
+      dst_local: false,
+      sport: sport,
+      dport: dport,
+      stats: TrafficStats::default(),
+      protocol: protocol.into(),
+      master_protocol: "".into(),
+      app_protocol: "".into(),
+      detection_completed: true,
+      tunnel: None,
+      icmp_type_code: (0, 0),
+    }
+  }
+
+  fn matches(filter: &str, flow: &FlowInfo) -> bool {
+    FlowFilter::parse(filter).unwrap().matches_flow(flow)
+  }
+
+  #[test]
+  fn parse_errors() {
+    assert!(FlowFilter::parse("").unwrap().is_empty());
+    assert!(FlowFilter::parse("port:https").is_err());
+    assert!(FlowFilter::parse("port:65536").is_err());
+    assert!(FlowFilter::parse("vlan:x").is_err());
+    assert!(FlowFilter::parse("10.0.0.0/33").is_err());
+    assert!(FlowFilter::parse("bogus").is_err());
+  }
+
+  #[test]
+  fn single_terms() {
+    let tls = flow(IPPROTO_TCP, "10.0.0.1", 50000, "192.0.2.1", 443, "TLS.Google");
+
+    assert!(matches("proto:tls", &tls));
+    assert!(matches("proto:GOOGLE", &tls));
+    assert!(!matches("proto:DNS", &tls));
+    assert!(matches("port:443", &tls));
+    assert!(matches("port:50000", &tls));
+    assert!(!matches("port:80", &tls));
+    assert!(matches("tcp", &tls));
+    assert!(!matches("udp", &tls));
+    assert!(matches("10.0.0.0/8", &tls));
+    assert!(matches("192.0.2.1", &tls));
+    assert!(!matches("172.16.0.0/12", &tls));
+    assert!(matches("remote", &tls));
+    assert!(!matches("local", &tls));
+  }
+
+  #[test]
+  fn icmp_has_no_ports() {
+    // an echo flow, keyed by the identifier
+    let ping = flow(IPPROTO_ICMP, "10.0.0.1", 8, "10.0.0.2", 8, "ICMP");
+
+    assert!(matches("icmp", &ping));
+    assert!(!matches("port:8", &ping));
+  }
+
+  #[test]
+  fn ipv6_networks() {
+    let dns = flow(IPPROTO_UDP, "2001:db8::1", 40000, "2001:4860::8888", 53, "DNS");
+
+    assert!(matches("2001:db8::/32", &dns));
+    assert!(matches("2001:4860::8888", &dns));
+    assert!(!matches("10.0.0.0/8", &dns));
+  }
+
+  #[test]
+  fn terms_are_anded() {
+    let tls = flow(IPPROTO_TCP, "10.0.0.1", 50000, "192.0.2.1", 443, "TLS");
+
+    assert!(matches("proto:TLS port:443 10.0.0.0/8", &tls));
+    assert!(matches("  tcp\t192.0.2.0/24  ", &tls));
+    assert!(!matches("proto:TLS port:80", &tls));
+    assert!(!matches("udp 10.0.0.0/8", &tls));
+  }
+
+  #[test]
+  fn host_terms() {
+    let host = HostInfo {
+      ip: "10.0.0.1".parse().unwrap(),
+      vlan: 10,
+      mac: MacAddress::nil(),
+      local: true,
+      stats: TrafficStats::default(),
+      num_flows: 1,
+    };
+    let mut matching_flows_hosts = HashSet::new();
+
+    let filter = FlowFilter::parse("10.0.0.0/24 vlan:10 local").unwrap();
+    assert!(filter.matches_host(&host, &matching_flows_hosts));
+    assert!(!FlowFilter::parse("remote").unwrap().matches_host(&host, &matching_flows_hosts));
+    assert!(!FlowFilter::parse("vlan:20").unwrap().matches_host(&host, &matching_flows_hosts));
+
+    // the flow terms are checked on the host flows
+    let filter = FlowFilter::parse("10.0.0.0/24 port:443").unwrap();
+    assert!(!filter.matches_host(&host, &matching_flows_hosts));
+    matching_flows_hosts.insert(host.key());
+    assert!(filter.matches_host(&host, &matching_flows_hosts));
+  }
+}
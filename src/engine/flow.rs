@@ -1,4 +1,5 @@
 use std::fmt;
+use std::rc::Rc;
 
 use super::types::*;
 use super::traffic_stats::TrafficStats;
@@ -7,24 +8,63 @@ use ndpi::Flow as NdpiFlow;
 use ndpi::NdpiProtocol;
 
 use super::host::Host;
+use super::tunnel::Tunnel;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlowEndReason {
+  IdleTimeout,
+  // the flow is still active, see PacketHandler::export_active
+  ActiveTimeout,
+  // e.g. the capture ended
+  ForcedEnd,
+}
+
+// The traffic of a flow since its previous export
+#[derive(Debug, Clone)]
+pub struct FlowRecord {
+  // the originating tuple
+  pub tuple: PacketTuple,
+  pub start: Timeval,
+  pub end: Timeval,
+  pub src2dst_pkts: u32,
+  pub dst2src_pkts: u32,
+  pub src2dst_bytes: u64,
+  pub dst2src_bytes: u64,
+  // the nDPI application protocol, or the master protocol if there is none
+  pub app_protocol: u16,
+  pub end_reason: FlowEndReason,
+  // (src2dst, dst2src), see Flow
+  pub icmp_type_code: (u16, u16),
+}
 
 pub struct Flow<> {
   pub l4proto: L4Proto,
-  pub shost: ManagedPtr<Host>,
-  pub dhost: ManagedPtr<Host>,
+  pub shost: Shared<Host>,
+  pub dhost: Shared<Host>,
   pub sport: u16,
   pub dport: u16,
   pub stats: TrafficStats,
   pub ndpi_flow: NdpiFlow,
+  pub tuple: PacketTuple,
   pub protocol: NdpiProtocol,
+  // the outer endpoints of the last tunneled packet
+  pub tunnel: Option<Tunnel>,
+  // (src2dst, dst2src) type << 8 | code of the last ICMP messages
+  pub icmp_type_code: (u16, u16),
   detection_completed: bool,
-  refs: u32,
+  // the counters at the last export, see export_record
+  last_export: Timeval,
+  exported_pkts: (u32, u32),
+  exported_bytes: (u64, u64),
 }
 
 impl Flow {
-  pub fn new(tuple: PacketTuple, mut shost: ManagedPtr<Host>, mut dhost: ManagedPtr<Host>) -> Flow {
-    shost.inc_refs();
-    dhost.inc_refs();
+  /* the hosts count the flow once, even when both endpoints are the same host */
+  pub fn new(tuple: PacketTuple, shost: Shared<Host>, dhost: Shared<Host>) -> Flow {
+    shost.borrow_mut().num_flows += 1;
+    if !Rc::ptr_eq(&shost, &dhost) {
+      dhost.borrow_mut().num_flows += 1;
+    }
 
     return Flow {
       shost: shost,
@@ -32,11 +72,16 @@ impl Flow {
       sport: tuple.sport,
       dport: tuple.dport,
       l4proto: tuple.proto.into(),
+      tuple: tuple,
       stats: Default::default(),
       ndpi_flow: NdpiFlow::new(),
       protocol: Default::default(),
+      tunnel: None,
+      icmp_type_code: (0, 0),
       detection_completed: false,
-      refs: 0,
+      last_export: Default::default(),
+      exported_pkts: (0, 0),
+      exported_bytes: (0, 0),
     };
   }
 
@@ -44,8 +89,13 @@ impl Flow {
     return self.stats.last_seen == Default::default();
   }
 
+  /* the flow source is the endpoint which sent the first packet */
   pub fn get_direction(&self, tuple: PacketTuple) -> PacketDir {
-    if tuple.sport == self.sport { PacketDir::Src2Dst } else { PacketDir::Dst2Src }
+    if (tuple.saddr == self.tuple.saddr) && (tuple.sport == self.tuple.sport) {
+      PacketDir::Src2Dst
+    } else {
+      PacketDir::Dst2Src
+    }
   }
 
   /* set protocol */
@@ -66,23 +116,63 @@ impl Flow {
   pub fn is_detection_completed(&self) -> bool {
     self.detection_completed
   }
+
+  /* the time of the last export, or the first packet time if never exported */
+  pub fn get_last_export(&self) -> Timeval {
+    if self.last_export == Default::default() { self.stats.first_seen } else { self.last_export }
+  }
+
+  /* the traffic since the previous export, None if there is no new traffic */
+  pub fn export_record(&mut self, reason: FlowEndReason) -> Option<FlowRecord> {
+    let pkts = (self.stats.src2dst_pkts, self.stats.dst2src_pkts);
+    let bytes = (self.stats.src2dst_bytes, self.stats.dst2src_bytes);
+
+    if pkts == self.exported_pkts {
+      return None;
+    }
+
+    let app_protocol = if self.protocol.app_protocol != Default::default() {
+      self.protocol.app_protocol
+    } else {
+      self.protocol.master_protocol
+    };
+
+    let record = FlowRecord {
+      tuple: self.tuple,
+      start: self.get_last_export(),
+      end: self.stats.last_seen,
+      src2dst_pkts: pkts.0 - self.exported_pkts.0,
+      dst2src_pkts: pkts.1 - self.exported_pkts.1,
+      src2dst_bytes: bytes.0 - self.exported_bytes.0,
+      dst2src_bytes: bytes.1 - self.exported_bytes.1,
+      app_protocol: app_protocol as u16,
+      end_reason: reason,
+      icmp_type_code: self.icmp_type_code,
+    };
+
+    self.last_export = self.stats.last_seen;
+    self.exported_pkts = pkts;
+    self.exported_bytes = bytes;
+    Some(record)
+  }
 }
 
 impl Drop for Flow {
   fn drop(&mut self) {
-    self.shost.dec_refs();
-    self.dhost.dec_refs();
+    self.shost.borrow_mut().num_flows -= 1;
+    if !Rc::ptr_eq(&self.shost, &self.dhost) {
+      self.dhost.borrow_mut().num_flows -= 1;
+    }
   }
 }
 
 impl LifetimeItem for Flow {
   fn get_last_seen(&self) -> Timeval { self.stats.last_seen }
-  fn get_refs(&mut self) -> &mut u32 { &mut self.refs }
 }
 
 impl fmt::Debug for Flow {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    write!(f, "Flow[{:?}][{:?}:{} -> {:?}:{}]", self.l4proto, self.shost,
-      self.sport, self.dhost, self.dport)
+    write!(f, "Flow[{:?}][{:?}:{} -> {:?}:{}]", self.l4proto, self.shost.borrow(),
+      self.sport, self.dhost.borrow(), self.dport)
   }
 }
@@ -1,30 +1,19 @@
 use std::collections::HashMap;
-use std::collections::hash_map::{Iter, IterMut};
+use std::collections::hash_map::Iter;
+use std::rc::Rc;
+use std::cell::RefCell;
 use super::types::*;
 
 pub struct GenericHash<K,V> {
-  items: HashMap<K, ManagedPtr<V>>,
+  items: HashMap<K, Shared<V>>,
   idle_timeout: i64,
 }
 
 pub trait LifetimeItem {
   fn get_last_seen(&self) -> Timeval;
-  fn get_refs(&mut self) -> &mut u32;
-
-  fn inc_refs(&mut self) {
-    (*self.get_refs()) += 1;
-  }
-
-  fn dec_refs(&mut self) {
-    let refs = self.get_refs();
-
-    if *refs > 0 {
-      *refs -= 1;
-    }
-  }
 }
 
-impl <K: ::std::cmp::Eq + ::std::hash::Hash,V: LifetimeItem + ::std::fmt::Debug> GenericHash<K,V> {
+impl <K: ::std::cmp::Eq + ::std::hash::Hash + Clone,V: LifetimeItem + ::std::fmt::Debug> GenericHash<K,V> {
   pub fn new(idle_timeout: i64) -> GenericHash<K,V> {
     GenericHash {
       items: HashMap::new(),
@@ -32,37 +21,27 @@ impl <K: ::std::cmp::Eq + ::std::hash::Hash,V: LifetimeItem + ::std::fmt::Debug>
     }
   }
 
-  pub fn or_insert<F>(&mut self, k: K, item_builder: F) -> ManagedPtr<V>
+  pub fn or_insert<F>(&mut self, k: K, item_builder: F) -> Shared<V>
     where F: Fn() -> V {
-      let item: &ManagedPtr<V> = self.items.entry(k).or_insert_with(|| ManagedPtr::new(Box::new(item_builder())));
-      let ptr: ManagedPtr<V> = (*item).clone(); // duplicate ownership
-      ptr
+      let item = self.items.entry(k).or_insert_with(|| Rc::new(RefCell::new(item_builder())));
+      item.clone()
   }
 
-  pub fn purge_idle(&mut self, now: &SystemTime) {
+  /* items still referenced outside of the table (e.g. the hosts of a flow) are never purged.
+   * Returns the purged items. */
+  pub fn purge_idle(&mut self, now: &Timeval) -> Vec<Shared<V>> {
     let timeout = self.idle_timeout;
-
-    self.items.retain(|_k, v| {
-      ((*v.get_refs()) > 0) || {
-        let tstamp = now.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
-        let idle_sec = tstamp - v.get_last_seen().sec;
-        let is_idle = idle_sec > timeout;
-
-        if is_idle {
-          debug!("Purge Idle: {:?} ({} sec idle)", v, idle_sec);
-          v.free();
-        }
-
-        !is_idle
-      }
-    });
+    let idle: Vec<K> = self.items.iter().filter(|(_k, v)| {
+      (Rc::strong_count(v) == 1) && ((now.sec - v.borrow().get_last_seen().sec) > timeout)
+    }).map(|(k, _v)| k.clone()).collect();
+
+    idle.iter().filter_map(|k| self.items.remove(k)).map(|v| {
+      debug!("Purge Idle: {:?} ({} sec idle)", v.borrow(), now.sec - v.borrow().get_last_seen().sec);
+      v
+    }).collect()
   }
 
-  pub fn iter(&self) -> Iter<K, ManagedPtr<V>> {
+  pub fn iter(&self) -> Iter<K, Shared<V>> {
     self.items.iter()
   }
-
-  pub fn iter_mut(&mut self) -> IterMut<K, ManagedPtr<V>> {
-    self.items.iter_mut()
-  }
 }
@@ -2,44 +2,39 @@ use std::fmt;
 use super::types::*;
 use super::traffic_stats::TrafficStats;
 use super::generic_hash::LifetimeItem;
-use std::clone::Clone;
 
+#[derive(Clone)]
 pub struct Host {
-  pub ip: Ipv4Addr,
+  pub ip: IpAddr,
+  pub vlan: u16,
   pub mac: MacAddress,
+  // within the local networks
+  pub local: bool,
+  // src2dst: sent by the host, dst2src: received by the host
   pub stats: TrafficStats,
-  refs: u32,
+  // the flows referencing the host, see Flow::new
+  pub num_flows: u32,
 }
 
 impl Host {
-  pub fn new(ip: Ipv4Addr, mac: MacAddress) -> Host {
+  pub fn new(key: HostKey, mac: MacAddress, local: bool) -> Host {
     Host {
-      ip: ip,
+      ip: key.ip,
+      vlan: key.vlan,
       mac: mac,
+      local: local,
       stats: Default::default(),
-      refs: 0,
+      num_flows: 0,
     }
   }
 }
 
 impl LifetimeItem for Host {
   fn get_last_seen(&self) -> Timeval { self.stats.last_seen }
-  fn get_refs(&mut self) -> &mut u32 { &mut self.refs }
 }
 
 impl fmt::Debug for Host {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    write!(f, "Host[{}][{}]", self.ip, self.mac)
-  }
-}
-
-impl Clone for Host {
-  fn clone(&self) -> Host {
-    Host {
-      ip: self.ip,
-      mac: self.mac,
-      stats: self.stats.clone(),
-      refs: 0,
-    }
+    write!(f, "Host[{}][{}][vlan {}]", self.ip, self.mac, self.vlan)
   }
 }
@@ -1,8 +1,12 @@
 pub mod host;
 pub mod flow;
-pub mod managed_ptr;
 pub mod types;
 pub mod packet_handler;
+pub mod datalink;
+pub mod tunnel;
+pub mod filter;
+pub mod snapshot;
+pub mod aggregate;
 
-mod traffic_stats;
+pub mod traffic_stats;
 mod generic_hash;
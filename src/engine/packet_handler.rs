@@ -1,59 +1,90 @@
-use etherparse::{SlicedPacket,LinkSlice,InternetSlice,TransportSlice};
+use etherparse::{SlicedPacket,InternetSlice,TransportSlice};
 
-use std::cmp::{max, Reverse, Ordering};
+use std::collections::HashMap;
+use std::sync::Arc;
 use pcap::PacketHeader;
 use ndpi::{DetectionModule, NdpiProtocol};
 
 use super::generic_hash::GenericHash;
-use super::flow::Flow;
+use super::flow::{Flow, FlowRecord, FlowEndReason};
 use super::host::Host;
+use super::traffic_stats::TrafficStats;
+use super::snapshot::{Snapshot, FlowInfo, HostInfo, ProtocolStats, Watched};
 use super::types::*;
+use super::datalink::decapsulate;
+use super::tunnel::{Tunnel, decapsulate_tunnel};
 
-const FLOW_IDLE_TIMEOUT_SEC: i64 = 60;
-const HOST_IDLE_TIMEOUT_SEC: i64 = 300;
 const MAX_PACKETS_BEFORE_DETECTION_GIVEUP: u32 = 8;
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+pub struct ParsedPacket<'a> {
+  pub tuple: PacketTuple,
+  pub srcmac: MacAddress,
+  pub dstmac: MacAddress,
+  // the IP packet, the inner one for tunnels
+  pub ip: &'a [u8],
+  pub tunnel: Option<Tunnel>,
+  // type << 8 | code of the ICMP messages
+  pub icmp_type_code: Option<u16>,
+}
+
+// The names of an nDPI protocol, see PacketHandler::protocol_names
+#[derive(Clone)]
+struct ProtocolNames {
+  protocol: Arc<str>,
+  master: Arc<str>,
+  app: Arc<str>,
+}
 
 pub struct PacketHandler {
   flows: GenericHash<PacketTuple, Flow>,
-  hosts: GenericHash<u32, Host>,
+  hosts: GenericHash<HostKey, Host>,
   detection_module: DetectionModule,
+  // account the tunnels inner packets
+  decap: bool,
+  // the hosts within are local, the others are remote
+  local_networks: Vec<IpNetwork>,
+  // all the accounted traffic
+  totals: TrafficStats,
+  // by (master, app) protocol, see account_protocol
+  protocols: HashMap<(u16, u16), ProtocolStats>,
+  purged_flows: u64,
+  purged_hosts: u64,
+  // by (master, app) protocol, resolved once for all the snapshots
+  protocol_names: HashMap<(u16, u16), ProtocolNames>,
+  // the items whose history is part of the snapshots
+  watched: Watched,
 }
 
 impl PacketHandler {
-  pub fn new() -> PacketHandler {
+  pub fn new(flow_idle_timeout: i64, host_idle_timeout: i64, decap: bool, local_networks: Vec<IpNetwork>) -> PacketHandler {
     return PacketHandler {
-      flows: GenericHash::new(FLOW_IDLE_TIMEOUT_SEC),
-      hosts: GenericHash::new(HOST_IDLE_TIMEOUT_SEC),
+      flows: GenericHash::new(flow_idle_timeout),
+      hosts: GenericHash::new(host_idle_timeout),
       detection_module: DetectionModule::new(),
+      decap: decap,
+      local_networks: local_networks,
+      totals: Default::default(),
+      protocols: HashMap::new(),
+      purged_flows: 0,
+      purged_hosts: 0,
+      protocol_names: HashMap::new(),
+      watched: Default::default(),
     };
   }
 
-  fn parse_tuple(packet: &[u8]) -> Option<(PacketTuple, MacAddress, MacAddress, &[u8])> {
-    let mut tuple: PacketTuple = Default::default();
-    let mut srcmac: [u8; 6] = Default::default();
-    let mut dstmac: [u8; 6] = Default::default();
-    let mut ip_ptr = packet;
+  /* the tuple of an IP packet and its transport payload */
+  fn parse_ip(ip: &[u8], vlan: u16) -> Option<(PacketTuple, &[u8])> {
+    let mut tuple = PacketTuple { vlan: vlan, ..Default::default() };
+    let mut payload: &[u8] = &[];
 
-    match SlicedPacket::from_ethernet(packet) {
-      Err(value) => println!("Err {:?}", value),
+    match SlicedPacket::from_ip(ip) {
+      Err(value) => debug!("Err {:?}", value),
       Ok(value) => {
-        match value.link {
-          Some(linkslice) => {
-            match linkslice {
-              LinkSlice::Ethernet2(ethlinkslice) => {
-                let ethlink = ethlinkslice.to_header();
-                srcmac = ethlink.source;
-                dstmac = ethlink.destination;
-
-                //println!("{} -> {} [{:04x}]",
-                  //MacAddress::new(ethlink.source).to_hex_string(),
-                  //MacAddress::new(ethlink.destination).to_hex_string(),
-                  //ethlink.ether_type);
-              }
-            }
-          },
-          None => (),
-        }
+        payload = value.payload;
 
         match value.ip {
           Some(ipslice) => {
@@ -65,13 +96,23 @@ impl PacketHandler {
                 let srvip = Ipv4Addr::new(src[0], src[1], src[2], src[3]);
                 let dstip = Ipv4Addr::new(dst[0], dst[1], dst[2], dst[3]);
 
-                ip_ptr = ipv4slice.slice();
                 tuple.saddr = srvip.into();
                 tuple.daddr = dstip.into();
                 tuple.proto = ipv4hdr.protocol;
               },
-              InternetSlice::Ipv6(_ipv6slice, _extheader) => {
-                // TODO
+              InternetSlice::Ipv6(ipv6slice, extheaders) => {
+                let ipv6hdr = ipv6slice.to_header();
+
+                tuple.saddr = Ipv6Addr::from(ipv6hdr.source).into();
+                tuple.daddr = Ipv6Addr::from(ipv6hdr.destination).into();
+                tuple.proto = ipv6hdr.next_header;
+
+                // skip the extension headers
+                for extheader in extheaders.iter() {
+                  if let Some((_, ref extslice)) = *extheader {
+                    tuple.proto = extslice.next_header();
+                  }
+                }
               },
             }
           },
@@ -88,11 +129,13 @@ impl PacketHandler {
                 let tcphdr = tcpslice.to_header();
                 sport = tcphdr.source_port;
                 dport = tcphdr.destination_port;
+                tuple.proto = IPPROTO_TCP;
               },
               TransportSlice::Udp(udpslice) => {
                 let udphdr = udpslice.to_header();
                 sport = udphdr.source_port;
                 dport = udphdr.destination_port;
+                tuple.proto = IPPROTO_UDP;
               },
             }
 
@@ -101,98 +144,582 @@ impl PacketHandler {
               tuple.dport = dport;
             }
           },
-          None => (),
+          None => PacketHandler::parse_transport_payload(&mut tuple, value.payload),
         }
       }
     }
 
     if tuple.ok() {
-      return Some((tuple, MacAddress::new(srcmac), MacAddress::new(dstmac), ip_ptr));
+      return Some((tuple, payload));
     }
 
     None
   }
 
-  pub fn process_packet(&mut self, header: &PacketHeader, packet: &[u8]) {
-    match PacketHandler::parse_tuple(packet) {
-      Some((tuple, srcmac, dstmac, ip_ptr)) => {
+  /* linktype is the pcap link type of the capture. With decap, the tunneled
+   * packets are parsed as the inner packet */
+  fn parse_tuple(linktype: i32, packet: &[u8], decap: bool) -> Option<ParsedPacket> {
+    let link = decapsulate(linktype, packet)?;
+    let (tuple, payload) = PacketHandler::parse_ip(link.ip, link.vlan)?;
+
+    if decap {
+      if let Some((tunnel, inner)) = decapsulate_tunnel(&tuple, payload) {
+        let vlan = if inner.vlan != 0 { inner.vlan } else { link.vlan };
+
+        if let Some((inner_tuple, inner_payload)) = PacketHandler::parse_ip(inner.ip, vlan) {
+          // inner IP packets have no MAC addresses
+          let (srcmac, dstmac) = if inner.srcmac.is_nil() { (link.srcmac, link.dstmac) } else { (inner.srcmac, inner.dstmac) };
+
+          return Some(ParsedPacket {
+            tuple: inner_tuple,
+            srcmac: srcmac,
+            dstmac: dstmac,
+            ip: inner.ip,
+            tunnel: Some(tunnel),
+            icmp_type_code: PacketHandler::icmp_type_code(&inner_tuple, inner_payload),
+          });
+        }
+      }
+    }
+
+    Some(ParsedPacket {
+      tuple: tuple,
+      srcmac: link.srcmac,
+      dstmac: link.dstmac,
+      ip: link.ip,
+      tunnel: None,
+      icmp_type_code: PacketHandler::icmp_type_code(&tuple, payload),
+    })
+  }
+
+  fn icmp_type_code(tuple: &PacketTuple, payload: &[u8]) -> Option<u16> {
+    if is_icmp(tuple.proto) && (payload.len() >= 2) {
+      Some(((payload[0] as u16) << 8) | (payload[1] as u16))
+    } else {
+      None
+    }
+  }
+
+  /* ports for the transport protocols not parsed by etherparse, see PacketTuple */
+  fn parse_transport_payload(tuple: &mut PacketTuple, payload: &[u8]) {
+    match tuple.proto {
+      IPPROTO_ICMP | IPPROTO_ICMPV6 if payload.len() >= 8 => {
+        let (icmp_type, code) = (payload[0], payload[1]);
+        let is_echo = match (tuple.proto, icmp_type) {
+          (IPPROTO_ICMP, ICMP_ECHO_REQUEST) | (IPPROTO_ICMP, ICMP_ECHO_REPLY) => true,
+          (IPPROTO_ICMPV6, ICMPV6_ECHO_REQUEST) | (IPPROTO_ICMPV6, ICMPV6_ECHO_REPLY) => true,
+          _ => false,
+        };
+
+        if is_echo {
+          let identifier = ((payload[4] as u16) << 8) | (payload[5] as u16);
+          tuple.sport = identifier;
+          tuple.dport = identifier;
+        } else {
+          tuple.dport = ((icmp_type as u16) << 8) | (code as u16);
+        }
+      },
+      IPPROTO_SCTP if payload.len() >= 4 => {
+        tuple.sport = ((payload[0] as u16) << 8) | (payload[1] as u16);
+        tuple.dport = ((payload[2] as u16) << 8) | (payload[3] as u16);
+      },
+      _ => (),
+    }
+  }
+
+  pub fn process_packet(&mut self, linktype: i32, header: &PacketHeader, packet: &[u8]) {
+    match PacketHandler::parse_tuple(linktype, packet, self.decap) {
+      Some(ParsedPacket { tuple, srcmac, dstmac, ip, tunnel, icmp_type_code }) => {
         let when = header.ts.into();
-        let ip_size = max(packet.len() as isize - (ip_ptr.as_ptr() as isize - packet.as_ptr() as isize), 0);
 
-        let mut srchost = self.hosts.or_insert(tuple.saddr, || Host::new(tuple.saddr.into(), srcmac));
-        let mut dsthost = self.hosts.or_insert(tuple.daddr, || Host::new(tuple.daddr.into(), dstmac));
+        // only checked for the new hosts
+        let local_networks = &self.local_networks;
+        let is_local = |ip: &IpAddr| local_networks.iter().any(|network| network.contains(ip));
+        let srchost = self.hosts.or_insert(tuple.src_host(), || Host::new(tuple.src_host(), srcmac, is_local(&tuple.saddr)));
+        let dsthost = self.hosts.or_insert(tuple.dst_host(), || Host::new(tuple.dst_host(), dstmac, is_local(&tuple.daddr)));
 
-        let mut flow = self.flows.or_insert(tuple, || Flow::new(tuple, srchost.clone(), dsthost.clone()));
+        // replies must join the originating flow, so lookup by the direction independent key
+        let flow = self.flows.or_insert(tuple.key(), || Flow::new(tuple, srchost.clone(), dsthost.clone()));
+        let mut flow = flow.borrow_mut();
 
+        // srchost and dsthost may be the same host, never borrow both at once.
+        // Some link types only have the source MAC, or none
         if flow.just_created() {
-          srchost.mac = srcmac;
-          dsthost.mac = dstmac;
+          if !srcmac.is_nil() {
+            srchost.borrow_mut().mac = srcmac;
+          }
+          if !dstmac.is_nil() {
+            dsthost.borrow_mut().mac = dstmac;
+          }
+        }
+
+        if tunnel.is_some() {
+          flow.tunnel = tunnel;
         }
 
         let dir = flow.get_direction(tuple);
+
+        if let Some(type_code) = icmp_type_code {
+          match dir {
+            PacketDir::Src2Dst => flow.icmp_type_code.0 = type_code,
+            PacketDir::Dst2Src => flow.icmp_type_code.1 = type_code,
+          }
+        }
+
         flow.stats.account_packet(when, dir, header.len);
-        srchost.stats.account_packet(when, dir, header.len);
-        dsthost.stats.account_packet(when, dir, header.len);
+        self.totals.account_packet(when, PacketDir::Src2Dst, header.len);
+        srchost.borrow_mut().stats.account_packet(when, PacketDir::Src2Dst, header.len);
+        dsthost.borrow_mut().stats.account_packet(when, PacketDir::Dst2Src, header.len);
 
-        if !flow.is_detection_completed() {
-          let protocol = self.detection_module.dissect_packet(&mut flow.ndpi_flow, ip_ptr, ip_size as u32, header.ts, dir.is_src2_dest());
+        let detection_completed = flow.is_detection_completed();
+
+        if !detection_completed {
+          let protocol = self.detection_module.dissect_packet(&mut flow.ndpi_flow, ip, ip.len() as u32, header.ts, dir.is_src2_dest());
           flow.set_protocol(protocol);
 
           if !flow.is_detection_completed() && flow.stats.packets() >= MAX_PACKETS_BEFORE_DETECTION_GIVEUP {
-            let protocol = self.detection_module.guess_protocol(tuple.proto, tuple.saddr, tuple.sport, tuple.daddr, tuple.dport);
+            let orig = flow.tuple;
+            let protocol = self.detection_module.guess_protocol(orig.proto,
+              ip_to_u32(&orig.saddr), orig.sport, ip_to_u32(&orig.daddr), orig.dport);
             flow.set_detected_protocol(protocol);
           }
         }
 
+        if detection_completed {
+          self.account_protocol(&flow.protocol, 1, header.len as u64);
+        } else if flow.is_detection_completed() {
+          // the packets seen during the detection
+          self.account_protocol(&flow.protocol, flow.stats.packets() as u64, flow.stats.bytes());
+        }
+
         debug!("{:?} [{:?}] ({} packets, {} bytes)", flow, self.detection_module.get_protocol_name(&flow.protocol), flow.stats.packets(), flow.stats.bytes());
       },
       None => ()
     }
   }
 
-  pub fn purge_idle(&mut self, now: &SystemTime) {
+  /* the traffic of a flow is accounted to its protocol once, when the detection
+   * completes or the flow expires, so that the protocols counters only increase */
+  fn account_protocol(&mut self, proto: &NdpiProtocol, packets: u64, bytes: u64) {
+    let protocol = self.protocols.entry((proto.master_protocol, proto.app_protocol)).or_insert_with(Default::default);
+    protocol.packets += packets;
+    protocol.bytes += bytes;
+  }
+
+  /* returns the records of the expired flows */
+  pub fn purge_idle(&mut self, now: &Timeval) -> Vec<FlowRecord> {
     debug!("purge_idle");
 
-    self.flows.purge_idle(now);
-    self.hosts.purge_idle(now);
+    let mut records = Vec::new();
+
+    for flow in self.flows.purge_idle(now) {
+      let mut flow = flow.borrow_mut();
+
+      // expired before the end of the detection
+      if !flow.is_detection_completed() {
+        self.account_protocol(&flow.protocol, flow.stats.packets() as u64, flow.stats.bytes());
+      }
+
+      self.purged_flows += 1;
+
+      if let Some(record) = flow.export_record(FlowEndReason::IdleTimeout) {
+        records.push(record);
+      }
+    }
+
+    // after the flows, which hold references to their hosts
+    self.purged_hosts += self.hosts.purge_idle(now).len() as u64;
+    records
+  }
+
+  /* the records of the flows exported more than active_timeout seconds ago,
+   * 0 exports all the flows with new traffic */
+  pub fn export_active(&mut self, now: &Timeval, active_timeout: i64, reason: FlowEndReason) -> Vec<FlowRecord> {
+    self.flows.iter().filter_map(|(_, flow)| {
+      let mut flow = flow.borrow_mut();
+
+      if (now.sec - flow.get_last_export().sec) >= active_timeout {
+        flow.export_record(reason)
+      } else {
+        None
+      }
+    }).collect()
   }
 
   pub fn update_stats(&mut self, now: &Timeval) {
-    for (_, flow) in self.flows.iter_mut() {
-      flow.stats.update(*now);
+    for (_, flow) in self.flows.iter() {
+      flow.borrow_mut().stats.update(*now);
     }
 
-    for (_, host) in self.hosts.iter_mut() {
-      host.stats.update(*now);
+    for (_, host) in self.hosts.iter() {
+      host.borrow_mut().stats.update(*now);
     }
+
+    self.totals.update(*now);
   }
 
-  pub fn get_protocol_name(&self, proto: &NdpiProtocol) -> String {
-    return self.detection_module.get_protocol_name(proto);
+  pub fn sample_rates(&mut self, now: &Timeval) {
+    for (_, flow) in self.flows.iter() {
+      flow.borrow_mut().stats.sample_rates(*now);
+    }
+
+    for (_, host) in self.hosts.iter() {
+      host.borrow_mut().stats.sample_rates(*now);
+    }
+
+    self.totals.sample_rates(*now);
   }
 
-  pub fn top_hosts(&self) -> Vec<&Host> {
-    // TODO improve
-    let mut v : Vec<&Host> = Vec::new();
+  /* key is the (master, app) protocol */
+  fn protocol_names(cache: &mut HashMap<(u16, u16), ProtocolNames>, detection_module: &DetectionModule,
+      key: (u16, u16)) -> ProtocolNames {
+    cache.entry(key).or_insert_with(|| {
+      let proto = NdpiProtocol { master_protocol: key.0, app_protocol: key.1, ..Default::default() };
+      let master = NdpiProtocol { app_protocol: key.0, ..Default::default() };
+      let app = NdpiProtocol { app_protocol: key.1, ..Default::default() };
+
+      ProtocolNames {
+        protocol: detection_module.get_protocol_name(&proto).into(),
+        master: detection_module.get_protocol_name(&master).into(),
+        app: detection_module.get_protocol_name(&app).into(),
+      }
+    }).clone()
+  }
 
-    for (_, val) in self.hosts.iter() {
-      v.push(&(**val));
+  /* the history of the other flows and hosts is left out of the snapshots */
+  pub fn set_watched(&mut self, watched: Watched) {
+    self.watched = watched;
+  }
+
+  pub fn snapshot(&mut self, now: &Timeval) -> Snapshot {
+    let mut flows = Vec::new();
+    let mut protocols: HashMap<String, ProtocolStats> = HashMap::new();
+
+    for (key, flow) in self.flows.iter() {
+      let flow = flow.borrow();
+      let shost = flow.shost.borrow();
+      let dhost = flow.dhost.borrow();
+      let key = (flow.protocol.master_protocol, flow.protocol.app_protocol);
+      let names = PacketHandler::protocol_names(&mut self.protocol_names, &self.detection_module, key);
+      let stats = if self.watched.flow == Some(*key) { flow.stats.clone() } else { flow.stats.without_history() };
+
+      flows.push(FlowInfo {
+        key: *key,
+        vlan: key.vlan,
+        l4proto: flow.l4proto,
+        proto: flow.tuple.proto,
+        saddr: shost.ip,
+        smac: shost.mac,
+        daddr: dhost.ip,
+        dmac: dhost.mac,
+        src_local: shost.local,
+        dst_local: dhost.local,
+        sport: flow.sport,
+        dport: flow.dport,
+        stats: stats,
+        protocol: names.protocol,
+        master_protocol: names.master,
+        app_protocol: names.app,
+        detection_completed: flow.is_detection_completed(),
+        tunnel: flow.tunnel,
+        icmp_type_code: flow.icmp_type_code,
+      });
+    }
+
+    for (key, stats) in self.protocols.iter() {
+      let names = PacketHandler::protocol_names(&mut self.protocol_names, &self.detection_module, *key);
+      let protocol = protocols.entry(names.protocol.to_string()).or_insert_with(Default::default);
+      protocol.packets += stats.packets;
+      protocol.bytes += stats.bytes;
     }
 
-    v.sort_by_key(|host| Reverse(host.stats.bytes()));
-    v
+    let hosts = self.hosts.iter().map(|(_, host)| {
+      let host = host.borrow();
+      let key = HostKey { vlan: host.vlan, ip: host.ip };
+      let stats = if self.watched.host == Some(key) { host.stats.clone() } else { host.stats.without_history() };
+
+      HostInfo {
+        ip: host.ip,
+        vlan: host.vlan,
+        mac: host.mac,
+        local: host.local,
+        stats: stats,
+        num_flows: host.num_flows,
+      }
+    }).collect();
+
+    let mut snapshot = Snapshot {
+      when: *now,
+      flows: flows,
+      hosts: hosts,
+      totals: self.totals.clone(),
+      protocols: protocols,
+      purged_flows: self.purged_flows,
+      purged_hosts: self.purged_hosts,
+      flow_index: HashMap::new(),
+    };
+
+    snapshot.index_flows();
+    snapshot
   }
+}
 
-  pub fn top_flows(&self) -> Vec<&Flow> {
-    // TODO improve
-    let mut v : Vec<&Flow> = Vec::new();
+#[cfg(test)]
+mod tests {
+  use libc;
+  use pcap::PacketHeader;
+  use super::*;
+  use super::super::datalink::{DLT_EN10MB, DLT_RAW};
+
+  const FLOW_IDLE_TIMEOUT: i64 = 60;
+  const HOST_IDLE_TIMEOUT: i64 = 10;
+
+  /* an Ethernet frame carrying an IPv4 UDP packet with a 4 bytes payload */
+  fn udp_packet(saddr: [u8; 4], daddr: [u8; 4], sport: u16, dport: u16) -> Vec<u8> {
+    let mut packet = vec![
+      0x02, 0, 0, 0, 0, daddr[3], 0x02, 0, 0, 0, 0, saddr[3], 0x08, 0x00,
+      0x45, 0, 0, 32, 0, 0, 0x40, 0, 64, IPPROTO_UDP, 0, 0,
+      saddr[0], saddr[1], saddr[2], saddr[3],
+      daddr[0], daddr[1], daddr[2], daddr[3],
+      (sport >> 8) as u8, sport as u8, (dport >> 8) as u8, dport as u8, 0, 12, 0, 0,
+    ];
+
+    packet.extend_from_slice(&[1, 2, 3, 4]);
+    packet
+  }
 
-    for (_, flow) in self.flows.iter() {
-      v.push(&(**flow));
+  fn process(handler: &mut PacketHandler, sec: i64, packet: &[u8]) {
+    let header = PacketHeader {
+      ts: libc::timeval { tv_sec: sec as libc::time_t, tv_usec: 0 },
+      caplen: packet.len() as u32,
+      len: packet.len() as u32,
+    };
+
+    handler.process_packet(DLT_EN10MB, &header, packet);
+  }
+
+  fn at(sec: i64) -> Timeval {
+    Timeval { sec: sec, usec: 0 }
+  }
+
+  /* an IPv6 UDP packet with a 4 bytes payload, optionally after an hop-by-hop options header */
+  fn ipv6_udp_packet(hop_by_hop: bool) -> Vec<u8> {
+    let payload_len = if hop_by_hop { 20 } else { 12 };
+    let mut packet = vec![0x60, 0, 0, 0, 0, payload_len, if hop_by_hop { 0 } else { IPPROTO_UDP }, 64];
+
+    packet.extend_from_slice(&Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).octets());
+    packet.extend_from_slice(&Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2).octets());
+    if hop_by_hop {
+      // PadN option filling the 8 bytes header
+      packet.extend_from_slice(&[IPPROTO_UDP, 0, 1, 4, 0, 0, 0, 0]);
+    }
+    packet.extend_from_slice(&[0x9c, 0x40, 0, 53, 0, 12, 0, 0, 1, 2, 3, 4]);
+    packet
+  }
+
+  /* a raw IPv4 packet */
+  fn ipv4_packet(proto: u8, saddr: [u8; 4], daddr: [u8; 4], payload: &[u8]) -> Vec<u8> {
+    let len = 20 + payload.len();
+    let mut packet = vec![0x45, 0, (len >> 8) as u8, len as u8, 0, 0, 0x40, 0, 64, proto, 0, 0];
+
+    packet.extend_from_slice(&saddr);
+    packet.extend_from_slice(&daddr);
+    packet.extend_from_slice(payload);
+    packet
+  }
+
+  fn process_ip(handler: &mut PacketHandler, sec: i64, packet: &[u8]) {
+    let header = PacketHeader {
+      ts: libc::timeval { tv_sec: sec as libc::time_t, tv_usec: 0 },
+      caplen: packet.len() as u32,
+      len: packet.len() as u32,
+    };
+
+    handler.process_packet(DLT_RAW, &header, packet);
+  }
+
+  fn num_flows(snapshot: &Snapshot, ip: [u8; 4]) -> Option<u32> {
+    let ip: IpAddr = Ipv4Addr::from(ip).into();
+    snapshot.hosts.iter().find(|host| host.ip == ip).map(|host| host.num_flows)
+  }
+
+  #[test]
+  fn hosts_outlive_their_flows() {
+    let mut handler = PacketHandler::new(FLOW_IDLE_TIMEOUT, HOST_IDLE_TIMEOUT, false, Vec::new());
+    let (a, b) = ([10, 0, 0, 1], [10, 0, 0, 2]);
+
+    process(&mut handler, 100, &udp_packet(a, b, 1000, 53));
+
+    // the hosts are idle past their timeout, but the flow is not
+    handler.purge_idle(&at(100 + FLOW_IDLE_TIMEOUT - 1));
+    let snapshot = handler.snapshot(&at(100 + FLOW_IDLE_TIMEOUT - 1));
+    assert_eq!(snapshot.flows.len(), 1);
+    assert_eq!(num_flows(&snapshot, a), Some(1));
+    assert_eq!(num_flows(&snapshot, b), Some(1));
+
+    // the hosts are purged together with their last flow
+    let records = handler.purge_idle(&at(100 + FLOW_IDLE_TIMEOUT + 1));
+    let snapshot = handler.snapshot(&at(100 + FLOW_IDLE_TIMEOUT + 1));
+    assert_eq!(records.len(), 1);
+    assert!(snapshot.flows.is_empty());
+    assert!(snapshot.hosts.is_empty());
+    assert_eq!(snapshot.purged_flows, 1);
+    assert_eq!(snapshot.purged_hosts, 2);
+  }
+
+  #[test]
+  fn num_flows_follows_the_flows_references() {
+    let mut handler = PacketHandler::new(FLOW_IDLE_TIMEOUT, HOST_IDLE_TIMEOUT, false, Vec::new());
+    let (a, b, c) = ([10, 0, 0, 1], [10, 0, 0, 2], [10, 0, 0, 3]);
+
+    process(&mut handler, 100, &udp_packet(a, b, 1000, 53));
+    process(&mut handler, 100, &udp_packet(a, c, 1001, 53));
+    // a reply joins the existing flow
+    process(&mut handler, 100, &udp_packet(b, a, 53, 1000));
+    // keeps the second flow alive
+    process(&mut handler, 150, &udp_packet(a, c, 1001, 53));
+
+    let snapshot = handler.snapshot(&at(150));
+    assert_eq!(snapshot.flows.len(), 2);
+    assert_eq!(num_flows(&snapshot, a), Some(2));
+    assert_eq!(num_flows(&snapshot, b), Some(1));
+    assert_eq!(num_flows(&snapshot, c), Some(1));
+
+    // only the first flow expires, with b which has no other flow
+    handler.purge_idle(&at(100 + FLOW_IDLE_TIMEOUT + 1));
+    let snapshot = handler.snapshot(&at(100 + FLOW_IDLE_TIMEOUT + 1));
+    assert_eq!(snapshot.flows.len(), 1);
+    assert_eq!(num_flows(&snapshot, a), Some(1));
+    assert_eq!(num_flows(&snapshot, b), None);
+    assert_eq!(num_flows(&snapshot, c), Some(1));
+  }
+
+  #[test]
+  fn num_flows_counts_the_flows_to_self_once() {
+    let mut handler = PacketHandler::new(FLOW_IDLE_TIMEOUT, HOST_IDLE_TIMEOUT, false, Vec::new());
+    let (a, b) = ([10, 0, 0, 1], [10, 0, 0, 2]);
+
+    process(&mut handler, 100, &udp_packet(a, a, 1000, 53));
+    process(&mut handler, 100, &udp_packet(a, b, 1001, 53));
+
+    let snapshot = handler.snapshot(&at(100));
+    assert_eq!(snapshot.flows.len(), 2);
+    assert_eq!(num_flows(&snapshot, a), Some(2));
+    assert_eq!(num_flows(&snapshot, b), Some(1));
+
+    process(&mut handler, 150, &udp_packet(a, b, 1001, 53));
+    handler.purge_idle(&at(100 + FLOW_IDLE_TIMEOUT + 1));
+    let snapshot = handler.snapshot(&at(100 + FLOW_IDLE_TIMEOUT + 1));
+    assert_eq!(num_flows(&snapshot, a), Some(1));
+  }
+
+  #[test]
+  fn ipv6_tuples() {
+    for hop_by_hop in [false, true].iter() {
+      let packet = ipv6_udp_packet(*hop_by_hop);
+      let parsed = PacketHandler::parse_tuple(DLT_RAW, &packet, false).unwrap();
+
+      // the transport protocol, not the extension header one
+      assert_eq!(parsed.tuple.proto, IPPROTO_UDP);
+      assert_eq!(parsed.tuple.saddr, "2001:db8::1".parse::<IpAddr>().unwrap());
+      assert_eq!(parsed.tuple.daddr, "2001:db8::2".parse::<IpAddr>().unwrap());
+      assert_eq!((parsed.tuple.sport, parsed.tuple.dport), (40000, 53));
+      assert_eq!(parsed.ip.len(), packet.len());
+    }
+  }
+
+  #[test]
+  fn ipv6_hosts() {
+    let mut handler = PacketHandler::new(FLOW_IDLE_TIMEOUT, HOST_IDLE_TIMEOUT, false, Vec::new());
+    let packet = ipv6_udp_packet(true);
+    let header = PacketHeader {
+      ts: libc::timeval { tv_sec: 100, tv_usec: 0 },
+      caplen: packet.len() as u32,
+      len: packet.len() as u32,
+    };
+
+    handler.process_packet(DLT_RAW, &header, &packet);
+    let snapshot = handler.snapshot(&at(100));
+    assert_eq!(snapshot.flows.len(), 1);
+    assert_eq!(snapshot.flows[0].proto, IPPROTO_UDP);
+    assert_eq!(snapshot.hosts.len(), 2);
+    assert!(snapshot.hosts.iter().all(|host| host.ip.is_ipv6() && (host.num_flows == 1)));
+  }
+
+  fn protocol_bytes(snapshot: &Snapshot) -> u64 {
+    snapshot.protocols.values().map(|protocol| protocol.bytes).sum()
+  }
+
+  #[test]
+  fn protocols_only_increase() {
+    let mut handler = PacketHandler::new(FLOW_IDLE_TIMEOUT, HOST_IDLE_TIMEOUT, false, Vec::new());
+    let packet = udp_packet([10, 0, 0, 1], [10, 0, 0, 2], 1000, 53);
+    let mut last = 0;
+
+    for i in 0..10 {
+      process(&mut handler, 100 + i, &packet);
+      let bytes = protocol_bytes(&handler.snapshot(&at(100 + i)));
+      assert!(bytes >= last, "{} < {}", bytes, last);
+      last = bytes;
+    }
+
+    // the detection gives up at MAX_PACKETS_BEFORE_DETECTION_GIVEUP at the latest
+    assert_eq!(last, 10 * packet.len() as u64);
+
+    // expired before the end of its detection
+    process(&mut handler, 120, &udp_packet([10, 0, 0, 1], [10, 0, 0, 3], 1001, 53));
+    assert_eq!(protocol_bytes(&handler.snapshot(&at(120))), last);
+    handler.purge_idle(&at(120 + FLOW_IDLE_TIMEOUT + 1));
+    let snapshot = handler.snapshot(&at(120 + FLOW_IDLE_TIMEOUT + 1));
+    assert!(snapshot.flows.is_empty());
+    assert_eq!(protocol_bytes(&snapshot), 11 * packet.len() as u64);
+  }
+
+  #[test]
+  fn icmp_echo_flows() {
+    let mut handler = PacketHandler::new(FLOW_IDLE_TIMEOUT, HOST_IDLE_TIMEOUT, false, Vec::new());
+    let (a, b) = ([10, 0, 0, 1], [10, 0, 0, 2]);
+    let echo = |icmp_type: u8, identifier: u8| vec![icmp_type, 0, 0, 0, 0, identifier, 0, 1, 1, 2, 3, 4];
+
+    // the reply joins the request flow, another identifier is another flow
+    process_ip(&mut handler, 100, &ipv4_packet(IPPROTO_ICMP, a, b, &echo(ICMP_ECHO_REQUEST, 1)));
+    process_ip(&mut handler, 100, &ipv4_packet(IPPROTO_ICMP, b, a, &echo(ICMP_ECHO_REPLY, 1)));
+    process_ip(&mut handler, 101, &ipv4_packet(IPPROTO_ICMP, a, b, &echo(ICMP_ECHO_REQUEST, 2)));
+
+    let snapshot = handler.snapshot(&at(101));
+    assert_eq!(snapshot.flows.len(), 2);
+
+    let key = PacketTuple { proto: IPPROTO_ICMP, saddr: Ipv4Addr::from(a).into(), daddr: Ipv4Addr::from(b).into(),
+      sport: 1, dport: 1, vlan: 0 };
+    let flow = snapshot.get_flow(&key.key()).unwrap();
+    assert_eq!(flow.saddr, key.saddr);
+    assert_eq!((flow.stats.src2dst_pkts, flow.stats.dst2src_pkts), (1, 1));
+    assert_eq!(flow.icmp_type_code, ((ICMP_ECHO_REQUEST as u16) << 8, (ICMP_ECHO_REPLY as u16) << 8));
+  }
+
+  #[test]
+  fn flows_without_ports() {
+    let (a, b) = ([10, 0, 0, 1], [10, 0, 0, 2]);
+
+    for &proto in &[IPPROTO_GRE, IPPROTO_ESP] {
+      let packet = ipv4_packet(proto, a, b, &[0; 8]);
+      let tuple = PacketHandler::parse_tuple(DLT_RAW, &packet, false).unwrap().tuple;
+
+      assert_eq!(tuple.proto, proto);
+      assert_eq!((tuple.sport, tuple.dport), (0, 0));
     }
 
-    //v.sort_by_key(|flow| Reverse(flow.stats.throughput));
-    v.sort_by(|a, b| b.stats.throughput.partial_cmp(&a.stats.throughput).unwrap_or(Ordering::Equal));
-    v
+    let packet = ipv4_packet(IPPROTO_SCTP, a, b, &[0x13, 0x88, 0x0b, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0]);
+    let tuple = PacketHandler::parse_tuple(DLT_RAW, &packet, false).unwrap().tuple;
+    assert_eq!(tuple.proto, IPPROTO_SCTP);
+    assert_eq!((tuple.sport, tuple.dport), (5000, 3000));
+
+    // tracked by address pair, both directions in a flow
+    let mut handler = PacketHandler::new(FLOW_IDLE_TIMEOUT, HOST_IDLE_TIMEOUT, false, Vec::new());
+    process_ip(&mut handler, 100, &ipv4_packet(IPPROTO_ESP, a, b, &[0; 8]));
+    process_ip(&mut handler, 100, &ipv4_packet(IPPROTO_ESP, b, a, &[0; 8]));
+    let snapshot = handler.snapshot(&at(100));
+    assert_eq!(snapshot.flows.len(), 1);
+    assert_eq!((snapshot.flows[0].stats.src2dst_pkts, snapshot.flows[0].stats.dst2src_pkts), (1, 1));
   }
 }
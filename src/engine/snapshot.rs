@@ -0,0 +1,396 @@
+use std::cmp::{Reverse, Ordering};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::types::*;
+use super::traffic_stats::TrafficStats;
+use super::tunnel::Tunnel;
+
+// Owned copies of the engine state, which can be kept around (e.g. by a
+// paused Ui) while the packet handler keeps accounting packets
+
+#[derive(Clone, Debug)]
+pub struct FlowInfo {
+  // the direction independent flow key, see PacketTuple::key
+  pub key: PacketTuple,
+  pub vlan: u16,
+  pub l4proto: L4Proto,
+  pub proto: u8,
+  pub saddr: IpAddr,
+  pub smac: MacAddress,
+  pub daddr: IpAddr,
+  pub dmac: MacAddress,
+  // within the local networks
+  pub src_local: bool,
+  pub dst_local: bool,
+  pub sport: u16,
+  pub dport: u16,
+  // without history unless watched, see Watched
+  pub stats: TrafficStats,
+  // shared by the flows of the same protocol
+  pub protocol: Arc<str>,
+  pub master_protocol: Arc<str>,
+  pub app_protocol: Arc<str>,
+  pub detection_completed: bool,
+  pub tunnel: Option<Tunnel>,
+  // (src2dst, dst2src) type << 8 | code of the last ICMP messages
+  pub icmp_type_code: (u16, u16),
+}
+
+// A flow from the local networks point of view
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FlowDirection {
+  // from a local host to a remote one
+  Outgoing,
+  // from a remote host to a local one
+  Incoming,
+  // between local hosts
+  Internal,
+  // between remote hosts, e.g. when the local networks are unknown
+  Transit,
+}
+
+impl FlowInfo {
+  pub fn direction(&self) -> FlowDirection {
+    match (self.src_local, self.dst_local) {
+      (true, false) => FlowDirection::Outgoing,
+      (false, true) => FlowDirection::Incoming,
+      (true, true) => FlowDirection::Internal,
+      (false, false) => FlowDirection::Transit,
+    }
+  }
+
+  /* the (upload, download) bytes per second: what the local side sends and
+   * receives. Without a single local side, src2dst and dst2src */
+  pub fn up_down_throughput(&self) -> (f64, f64) {
+    if self.direction() == FlowDirection::Incoming {
+      (self.stats.dst2src_throughput, self.stats.src2dst_throughput)
+    } else {
+      (self.stats.src2dst_throughput, self.stats.dst2src_throughput)
+    }
+  }
+
+  /* like up_down_throughput, the (upload, download) bytes */
+  pub fn up_down_bytes(&self) -> (u64, u64) {
+    if self.direction() == FlowDirection::Incoming {
+      (self.stats.dst2src_bytes, self.stats.src2dst_bytes)
+    } else {
+      (self.stats.src2dst_bytes, self.stats.dst2src_bytes)
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct HostInfo {
+  pub ip: IpAddr,
+  pub vlan: u16,
+  pub mac: MacAddress,
+  // within the local networks
+  pub local: bool,
+  // src2dst: sent by the host, dst2src: received by the host. Without
+  // history unless watched, see Watched
+  pub stats: TrafficStats,
+  pub num_flows: u32,
+}
+
+impl HostInfo {
+  pub fn key(&self) -> HostKey {
+    HostKey { vlan: self.vlan, ip: self.ip }
+  }
+}
+
+// Aggregated hosts, see Aggregator
+#[derive(Clone, Debug)]
+pub struct HostGroup {
+  pub name: String,
+  pub num_hosts: u32,
+  pub num_flows: u32,
+  pub stats: TrafficStats,
+}
+
+/* like Snapshot::top_hosts, Source and Destination sort by name */
+pub fn top_groups(groups: &[HostGroup], sort: FlowSort, reverse: bool) -> Vec<&HostGroup> {
+  let mut v: Vec<&HostGroup> = groups.iter().collect();
+
+  if !sort_by_stats(&mut v, sort, |group| &group.stats) {
+    match sort {
+      FlowSort::Source | FlowSort::Destination => v.sort_by(|a, b| a.name.cmp(&b.name)),
+      _ => v.sort_by_key(|group| Reverse(group.stats.bytes())),
+    }
+  }
+
+  if reverse {
+    v.reverse();
+  }
+
+  v
+}
+
+// The items whose history is part of the snapshots, e.g. the ones shown in
+// detail. Copying the history of every item would cost much more than the
+// rest of the snapshot
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Watched {
+  pub flow: Option<PacketTuple>,
+  pub host: Option<HostKey>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProtocolStats {
+  pub packets: u64,
+  pub bytes: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Snapshot {
+  pub when: Timeval,
+  pub flows: Vec<FlowInfo>,
+  pub hosts: Vec<HostInfo>,
+  pub totals: TrafficStats,
+  // by nDPI protocol name, the flows are counted once their detection
+  // completes or they expire, so these only increase
+  pub protocols: HashMap<String, ProtocolStats>,
+  // since the start
+  pub purged_flows: u64,
+  pub purged_hosts: u64,
+  // flow key to position in flows, see index_flows
+  pub flow_index: HashMap<PacketTuple, usize>,
+}
+
+impl Snapshot {
+  /* combines the snapshots of different shards, the same host can be on many shards */
+  pub fn merge(shards: &[Snapshot]) -> Snapshot {
+    let mut merged = Snapshot::default();
+    let mut hosts: HashMap<HostKey, HostInfo> = HashMap::new();
+
+    for shard in shards {
+      if shard.when - merged.when > 0. {
+        merged.when = shard.when;
+      }
+
+      merged.flows.extend(shard.flows.iter().cloned());
+      merged.totals.merge(&shard.totals);
+      merged.purged_flows += shard.purged_flows;
+      merged.purged_hosts += shard.purged_hosts;
+
+      for (name, stats) in shard.protocols.iter() {
+        let merged_stats = merged.protocols.entry(name.clone()).or_insert_with(Default::default);
+        merged_stats.packets += stats.packets;
+        merged_stats.bytes += stats.bytes;
+      }
+
+      for host in shard.hosts.iter() {
+        let merged_host = hosts.entry(host.key()).or_insert_with(|| HostInfo {
+          ip: host.ip,
+          vlan: host.vlan,
+          mac: host.mac,
+          local: host.local,
+          stats: Default::default(),
+          num_flows: 0,
+        });
+
+        if host.stats.last_seen - merged_host.stats.last_seen > 0. {
+          merged_host.mac = host.mac;
+        }
+
+        merged_host.stats.merge(&host.stats);
+        merged_host.num_flows += host.num_flows;
+      }
+    }
+
+    merged.hosts = hosts.into_iter().map(|(_, host)| host).collect();
+    merged.index_flows();
+    merged
+  }
+
+  /* must be called after changing flows, for get_flow */
+  pub fn index_flows(&mut self) {
+    self.flow_index = self.flows.iter().enumerate().map(|(idx, flow)| (flow.key, idx)).collect();
+  }
+
+  pub fn get_flow(&self, key: &PacketTuple) -> Option<&FlowInfo> {
+    self.flow_index.get(key).map(|idx| &self.flows[*idx])
+  }
+
+  /* Protocol has no meaning for hosts, they are sorted by bytes instead.
+   * Source and Destination both sort by address. */
+  pub fn top_hosts(&self, sort: FlowSort, reverse: bool) -> Vec<&HostInfo> {
+    let mut v: Vec<&HostInfo> = self.hosts.iter().collect();
+
+    if !sort_by_stats(&mut v, sort, |host| &host.stats) {
+      match sort {
+        FlowSort::Source | FlowSort::Destination => v.sort_by_key(|host| (host.ip, host.vlan)),
+        _ => v.sort_by_key(|host| Reverse(host.stats.bytes())),
+      }
+    }
+
+    if reverse {
+      v.reverse();
+    }
+
+    v
+  }
+
+  /* counters are sorted in descending order, the other columns in ascending order.
+   * Age sorts the oldest flows first. TX and RX are the upload and download, see
+   * FlowInfo::up_down_throughput */
+  pub fn top_flows(&self, sort: FlowSort, reverse: bool) -> Vec<&FlowInfo> {
+    let mut v: Vec<&FlowInfo> = self.flows.iter().collect();
+
+    match sort {
+      FlowSort::TxThroughput => v.sort_by(|a, b| cmp_rates(a.up_down_throughput().0, b.up_down_throughput().0)),
+      FlowSort::RxThroughput => v.sort_by(|a, b| cmp_rates(a.up_down_throughput().1, b.up_down_throughput().1)),
+      FlowSort::Protocol => v.sort_by(|a, b| a.protocol.cmp(&b.protocol)),
+      FlowSort::Source => v.sort_by_key(|flow| (flow.saddr, flow.sport)),
+      FlowSort::Destination => v.sort_by_key(|flow| (flow.daddr, flow.dport)),
+      _ => { sort_by_stats(&mut v, sort, |flow| &flow.stats); },
+    }
+
+    if reverse {
+      v.reverse();
+    }
+
+    v
+  }
+}
+
+fn cmp_rates(a: f64, b: f64) -> Ordering {
+  b.partial_cmp(&a).unwrap_or(Ordering::Equal)
+}
+
+/* sorts by the sort keys shared by flows and hosts, returns false if sort is not one of them */
+fn sort_by_stats<T, F>(v: &mut Vec<&T>, sort: FlowSort, stats: F) -> bool
+    where F: Fn(&T) -> &TrafficStats {
+  match sort {
+    FlowSort::Throughput => v.sort_by(|a, b| cmp_rates(stats(a).throughput, stats(b).throughput)),
+    FlowSort::TxThroughput => v.sort_by(|a, b| cmp_rates(stats(a).src2dst_throughput, stats(b).src2dst_throughput)),
+    FlowSort::RxThroughput => v.sort_by(|a, b| cmp_rates(stats(a).dst2src_throughput, stats(b).dst2src_throughput)),
+    FlowSort::RateShort => v.sort_by(|a, b| cmp_rates(stats(a).rate_short, stats(b).rate_short)),
+    FlowSort::RateMedium => v.sort_by(|a, b| cmp_rates(stats(a).rate_medium, stats(b).rate_medium)),
+    FlowSort::RateLong => v.sort_by(|a, b| cmp_rates(stats(a).rate_long, stats(b).rate_long)),
+    FlowSort::PeakThroughput => v.sort_by(|a, b| cmp_rates(stats(a).peak_throughput, stats(b).peak_throughput)),
+    FlowSort::Bytes => v.sort_by_key(|item| Reverse(stats(item).bytes())),
+    FlowSort::Packets => v.sort_by_key(|item| Reverse(stats(item).packets())),
+    FlowSort::Age => v.sort_by_key(|item| (stats(item).first_seen.sec, stats(item).first_seen.usec)),
+    _ => return false,
+  }
+
+  true
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::super::traffic_stats::RateSample;
+  use super::super::aggregate::{Aggregator, AggregationMode};
+
+  fn at(sec: i64) -> Timeval {
+    Timeval { sec: sec, usec: 0 }
+  }
+
+  /* history is (update time, bytes per second) */
+  fn stats(bytes: u64, history: &[(i64, f64)]) -> TrafficStats {
+    TrafficStats {
+      src2dst_pkts: 1,
+      src2dst_bytes: bytes,
+      history: history.iter().map(|&(sec, rate)| RateSample { when: at(sec), bytes: rate, packets: 1. }).collect(),
+      ..Default::default()
+    }
+  }
+
+  fn host(ip: &str, bytes: u64, num_flows: u32, history: &[(i64, f64)]) -> HostInfo {
+    HostInfo {
+      ip: ip.parse().unwrap(),
+      vlan: 0,
+      mac: MacAddress::nil(),
+      local: false,
+      stats: stats(bytes, history),
+      num_flows: num_flows,
+    }
+  }
+
+  fn flow(saddr: &str, daddr: &str, bytes: u64) -> FlowInfo {
+    let tuple = PacketTuple { vlan: 0, proto: IPPROTO_UDP, saddr: saddr.parse().unwrap(), daddr: daddr.parse().unwrap(), sport: 1000, dport: 53 };
+
+    FlowInfo {
+      key: tuple.key(),
+      vlan: 0,
+      l4proto: L4Proto::UDP,
+      proto: tuple.proto,
+      saddr: tuple.saddr,
+      smac: MacAddress::nil(),
+      daddr: tuple.daddr,
+      dmac: MacAddress::nil(),
+      src_local: false,
+      dst_local: false,
+      sport: tuple.sport,
+      dport: tuple.dport,
+      stats: stats(bytes, &[]),
+      protocol: "DNS".into(),
+      master_protocol: "Unknown".into(),
+      app_protocol: "DNS".into(),
+      detection_completed: true,
+      tunnel: None,
+      icmp_type_code: (0, 0),
+    }
+  }
+
+  fn shard(when: i64, flows: Vec<FlowInfo>, hosts: Vec<HostInfo>, totals: TrafficStats) -> Snapshot {
+    let mut protocols = HashMap::new();
+    protocols.insert("DNS".to_string(), ProtocolStats { packets: 1, bytes: totals.bytes() });
+
+    let mut snapshot = Snapshot {
+      when: at(when),
+      flows: flows,
+      hosts: hosts,
+      totals: totals,
+      protocols: protocols,
+      purged_flows: 1,
+      purged_hosts: 2,
+      flow_index: HashMap::new(),
+    };
+
+    snapshot.index_flows();
+    snapshot
+  }
+
+  #[test]
+  fn merge_shards() {
+    // 10.0.0.1 has a flow on each shard, the shards update their stats at the same times
+    let shards = [
+      shard(20, vec![flow("10.0.0.1", "10.0.1.1", 100)],
+        vec![host("10.0.0.1", 100, 1, &[(10, 1.), (15, 2.)]), host("10.0.1.1", 100, 1, &[])],
+        stats(100, &[(10, 1.), (15, 2.)])),
+      shard(19, vec![flow("10.0.0.1", "10.0.0.2", 50)],
+        vec![host("10.0.0.1", 50, 1, &[(15, 4.), (20, 8.)]), host("10.0.0.2", 50, 1, &[])],
+        stats(50, &[(15, 4.), (20, 8.)])),
+    ];
+    let merged = Snapshot::merge(&shards);
+
+    assert_eq!(merged.when, at(20));
+    assert_eq!(merged.flows.len(), 2);
+    assert!(shards.iter().all(|shard| merged.get_flow(&shard.flows[0].key).is_some()));
+    assert_eq!((merged.purged_flows, merged.purged_hosts), (2, 4));
+    assert_eq!(merged.protocols["DNS"].bytes, 150);
+
+    // the same host is merged, its samples by update time
+    assert_eq!(merged.hosts.len(), 3);
+    let ip: IpAddr = "10.0.0.1".parse().unwrap();
+    let host = merged.hosts.iter().find(|host| host.ip == ip).unwrap();
+    assert_eq!(host.stats.bytes(), 150);
+    assert_eq!(host.num_flows, 2);
+    let history: Vec<(i64, f64)> = host.stats.history.iter().map(|sample| (sample.when.sec, sample.bytes)).collect();
+    assert_eq!(history, vec![(10, 1.), (15, 6.), (20, 8.)]);
+
+    assert_eq!(merged.totals.bytes(), 150);
+    assert_eq!(merged.totals.bytes_history(), vec![1, 6, 8]);
+
+    // the groups span the shards
+    let aggregator = Aggregator::new(AggregationMode::Network, 24, 64, Vec::new());
+    let mut groups = aggregator.aggregate(merged.hosts.iter(), &merged.flows);
+    groups.sort_by(|a, b| a.name.cmp(&b.name));
+    let groups: Vec<(&str, u32, u32, u64)> = groups.iter()
+      .map(|group| (group.name.as_str(), group.num_hosts, group.num_flows, group.stats.bytes())).collect();
+    assert_eq!(groups, vec![("10.0.0.0/24", 2, 2, 200), ("10.0.1.0/24", 1, 1, 100)]);
+  }
+}
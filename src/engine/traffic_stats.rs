@@ -1,7 +1,28 @@
+use std::collections::VecDeque;
 use super::types::*;
 
+// Number of rate samples kept, one per stats update
+pub const HISTORY_SIZE: usize = 60;
+
+// Time constants of the smoothed rates, in seconds
+pub const RATE_SHORT_SEC: f64 = 1.;
+pub const RATE_MEDIUM_SEC: f64 = 10.;
+pub const RATE_LONG_SEC: f64 = 60.;
+// Interval of the samples of the smoothed rates, shorter than RATE_SHORT_SEC
+pub const RATE_SAMPLE_SEC: f64 = 0.5;
+
+// Per second rates over a stats update interval
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RateSample {
+  // the update time, the same for all the shards, see merge
+  pub when: Timeval,
+  pub bytes: f64,
+  pub packets: f64,
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct TrafficStats {
+  pub first_seen: Timeval,
   pub last_seen: Timeval,
   pub src2dst_pkts: u32,
   pub dst2src_pkts: u32,
@@ -9,14 +30,39 @@ pub struct TrafficStats {
   pub dst2src_bytes: u64,
 
   pub last_update: Timeval,
-  pub last_bytes: u64,
+  pub last_src2dst_bytes: u64,
+  pub last_dst2src_bytes: u64,
+  pub last_pkts: u32,
+  // bytes per second, throughput is the sum of the two directions
   pub throughput: f64,
+  pub src2dst_throughput: f64,
+  pub dst2src_throughput: f64,
+  // rate samples, independent of the stats updates, see sample_rates
+  pub last_sample: Timeval,
+  pub last_sample_bytes: u64,
+  // exponentially weighted moving averages of the sampled rates
+  pub rate_short: f64,
+  pub rate_medium: f64,
+  pub rate_long: f64,
+  // highest sampled rate since first seen
+  pub peak_throughput: f64,
+  // oldest sample first
+  pub history: VecDeque<RateSample>,
+}
+
+/* the weight of the new sample depends on the time elapsed since the previous one,
+ * so that the average does not depend on the update interval */
+fn ewma(average: f64, sample: f64, elapsed: f64, time_constant: f64) -> f64 {
+  let alpha = 1. - (-elapsed / time_constant).exp();
+  average + alpha * (sample - average)
 }
 
 impl TrafficStats {
   pub fn account_packet(&mut self, when: Timeval, dir: PacketDir, bytes: u32) {
     if self.last_update == Default::default() {
       self.last_update = when;
+      self.last_sample = when;
+      self.first_seen = when;
     }
 
     match dir {
@@ -33,13 +79,139 @@ impl TrafficStats {
   }
 
   pub fn update(&mut self, when: Timeval) {
-    let diff_bytes = self.bytes() - self.last_bytes;
-    self.throughput = (diff_bytes as f64) / (when - self.last_update);
+    let elapsed = when - self.last_update;
+
+    if elapsed <= 0. {
+      return;
+    }
+
+    let diff_pkts = self.packets() - self.last_pkts;
+    self.src2dst_throughput = ((self.src2dst_bytes - self.last_src2dst_bytes) as f64) / elapsed;
+    self.dst2src_throughput = ((self.dst2src_bytes - self.last_dst2src_bytes) as f64) / elapsed;
+    self.throughput = self.src2dst_throughput + self.dst2src_throughput;
 
-    self.last_bytes = self.bytes();
+    if self.history.len() >= HISTORY_SIZE {
+      self.history.pop_front();
+    }
+
+    self.history.push_back(RateSample {
+      when: when,
+      bytes: self.throughput,
+      packets: (diff_pkts as f64) / elapsed,
+    });
+
+    self.last_src2dst_bytes = self.src2dst_bytes;
+    self.last_dst2src_bytes = self.dst2src_bytes;
+    self.last_pkts = self.packets();
     self.last_update = when;
   }
 
+  /* advances the smoothed and peak rates. Called every RATE_SAMPLE_SEC, much more
+   * often than update, so that the short bursts are not averaged out */
+  pub fn sample_rates(&mut self, when: Timeval) {
+    let elapsed = when - self.last_sample;
+
+    // too close to the first packet for a meaningful rate
+    if (self.last_sample == Default::default()) || (elapsed < RATE_SAMPLE_SEC) {
+      return;
+    }
+
+    let rate = ((self.bytes() - self.last_sample_bytes) as f64) / elapsed;
+
+    // the first sample spans about the short time constant, so it is its average.
+    // The longer averages start from 0, as if idle before the first packet, so
+    // that an initial burst does not become their value
+    if self.last_sample_bytes == 0 {
+      self.rate_short = rate;
+    } else {
+      self.rate_short = ewma(self.rate_short, rate, elapsed, RATE_SHORT_SEC);
+    }
+
+    self.rate_medium = ewma(self.rate_medium, rate, elapsed, RATE_MEDIUM_SEC);
+    self.rate_long = ewma(self.rate_long, rate, elapsed, RATE_LONG_SEC);
+
+    if rate > self.peak_throughput {
+      self.peak_throughput = rate;
+    }
+
+    self.last_sample_bytes = self.bytes();
+    self.last_sample = when;
+  }
+
+  /* a copy without the history, which is much larger than the rest */
+  pub fn without_history(&self) -> TrafficStats {
+    TrafficStats { history: VecDeque::new(), ..*self }
+  }
+
+  /* the bytes rate samples, oldest first */
+  pub fn bytes_history(&self) -> Vec<u64> {
+    self.history.iter().map(|sample| sample.bytes as u64).collect()
+  }
+
+  /* sums the stats of the same item from different sources (e.g. shards) */
+  pub fn merge(&mut self, other: &TrafficStats) {
+    if (self.first_seen == Default::default()) || ((other.first_seen != Default::default())
+        && (other.first_seen - self.first_seen < 0.)) {
+      self.first_seen = other.first_seen;
+    }
+
+    if other.last_seen - self.last_seen > 0. {
+      self.last_seen = other.last_seen;
+    }
+
+    if other.last_update - self.last_update > 0. {
+      self.last_update = other.last_update;
+    }
+
+    if other.last_sample - self.last_sample > 0. {
+      self.last_sample = other.last_sample;
+    }
+
+    self.src2dst_pkts += other.src2dst_pkts;
+    self.dst2src_pkts += other.dst2src_pkts;
+    self.src2dst_bytes += other.src2dst_bytes;
+    self.dst2src_bytes += other.dst2src_bytes;
+    self.last_src2dst_bytes += other.last_src2dst_bytes;
+    self.last_dst2src_bytes += other.last_dst2src_bytes;
+    self.last_pkts += other.last_pkts;
+    self.last_sample_bytes += other.last_sample_bytes;
+    self.throughput += other.throughput;
+    self.src2dst_throughput += other.src2dst_throughput;
+    self.dst2src_throughput += other.dst2src_throughput;
+    self.rate_short += other.rate_short;
+    self.rate_medium += other.rate_medium;
+    self.rate_long += other.rate_long;
+
+    // the peaks of the sources may not be simultaneous, so they cannot be summed
+    self.peak_throughput = self.peak_throughput.max(other.peak_throughput);
+
+    // the sources may have a different number of samples, only the samples
+    // of the same update time are summed
+    let mut history = VecDeque::with_capacity(HISTORY_SIZE);
+    let (mut ours, mut theirs) = (self.history.iter().peekable(), other.history.iter().peekable());
+
+    loop {
+      let sample = match (ours.peek().map(|a| a.when), theirs.peek().map(|b| b.when)) {
+        (Some(a), Some(b)) if a == b => {
+          let (a, b) = (ours.next().unwrap(), theirs.next().unwrap());
+          RateSample { when: a.when, bytes: a.bytes + b.bytes, packets: a.packets + b.packets }
+        },
+        (Some(a), Some(b)) if a - b < 0. => *ours.next().unwrap(),
+        (_, Some(_)) => *theirs.next().unwrap(),
+        (Some(_), None) => *ours.next().unwrap(),
+        (None, None) => break,
+      };
+
+      history.push_back(sample);
+    }
+
+    while history.len() > HISTORY_SIZE {
+      history.pop_front();
+    }
+
+    self.history = history;
+  }
+
   pub fn packets(&self) -> u32 {
     self.src2dst_pkts + self.dst2src_pkts
   }
@@ -48,3 +220,71 @@ impl TrafficStats {
     self.src2dst_bytes + self.dst2src_bytes
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn at(sec: f64) -> Timeval {
+    Timeval { sec: sec.trunc() as i64, usec: (sec.fract() * 1e6).round() as i64 }
+  }
+
+  fn assert_close(value: f64, expected: f64) {
+    assert!((value - expected).abs() <= expected.abs() * 1e-9, "{} != {}", value, expected);
+  }
+
+  #[test]
+  fn ewma_does_not_depend_on_the_sample_interval() {
+    let once = ewma(0., 1000., 1., RATE_SHORT_SEC);
+    let twice = ewma(ewma(0., 1000., 0.5, RATE_SHORT_SEC), 1000., 0.5, RATE_SHORT_SEC);
+
+    assert_close(once, 1000. * (1. - (-1_f64).exp()));
+    assert_close(twice, once);
+    // a sample much longer than the time constant replaces the average
+    assert_close(ewma(500., 1000., 100. * RATE_LONG_SEC, RATE_LONG_SEC), 1000.);
+  }
+
+  #[test]
+  fn first_sample() {
+    let mut stats = TrafficStats::default();
+    stats.account_packet(at(100.), PacketDir::Src2Dst, 1000);
+
+    // too close to the first packet
+    stats.sample_rates(at(100.25));
+    assert_eq!(stats.rate_short, 0.);
+
+    stats.sample_rates(at(100.5));
+    assert_close(stats.rate_short, 2000.);
+    assert_close(stats.rate_medium, 2000. * (1. - (-0.5 / RATE_MEDIUM_SEC).exp()));
+    assert_close(stats.rate_long, 2000. * (1. - (-0.5 / RATE_LONG_SEC).exp()));
+    assert_close(stats.peak_throughput, 2000.);
+  }
+
+  #[test]
+  fn decay_and_peak() {
+    let mut stats = TrafficStats::default();
+    stats.account_packet(at(100.), PacketDir::Src2Dst, 1000);
+    stats.sample_rates(at(100.5));
+    let (short, medium, long) = (stats.rate_short, stats.rate_medium, stats.rate_long);
+
+    // idle for 10s, every average decays with its own time constant
+    for i in 1..21 {
+      stats.sample_rates(at(100.5 + (i as f64) * RATE_SAMPLE_SEC));
+    }
+
+    assert_close(stats.rate_short, short * (-10. / RATE_SHORT_SEC).exp());
+    assert_close(stats.rate_medium, medium * (-10. / RATE_MEDIUM_SEC).exp());
+    assert_close(stats.rate_long, long * (-10. / RATE_LONG_SEC).exp());
+    assert_close(stats.peak_throughput, 2000.);
+
+    // a smaller burst keeps the peak, a bigger one raises it
+    stats.account_packet(at(110.6), PacketDir::Dst2Src, 500);
+    stats.sample_rates(at(111.));
+    assert_close(stats.peak_throughput, 2000.);
+
+    stats.account_packet(at(111.2), PacketDir::Dst2Src, 3000);
+    stats.sample_rates(at(111.5));
+    assert_close(stats.peak_throughput, 6000.);
+    assert_eq!(stats.dst2src_bytes, 3500);
+  }
+}
@@ -0,0 +1,312 @@
+use super::types::*;
+use super::datalink::{decapsulate, ip_by_version, LinkInfo, DLT_EN10MB};
+
+const IPPROTO_IPIP: u8 = 4;
+const IPPROTO_IPV6: u8 = 41;
+const VXLAN_PORT: u16 = 4789;
+const GTP_U_PORT: u16 = 2152;
+
+const GRE_CHECKSUM: u16 = 0x8000;
+const GRE_KEY: u16 = 0x2000;
+const GRE_SEQUENCE: u16 = 0x1000;
+const GRE_VERSION_MASK: u16 = 0x0007;
+const GRE_PROTO_IPV4: u16 = 0x0800;
+const GRE_PROTO_IPV6: u16 = 0x86dd;
+const GRE_PROTO_ETHERNET: u16 = 0x6558;
+
+const VXLAN_HEADER_LEN: usize = 8;
+const VXLAN_FLAG_VNI: u8 = 0x08;
+
+const GTP_HEADER_LEN: usize = 8;
+const GTP_OPTIONAL_LEN: usize = 4;
+const GTP_VERSION_1: u8 = 1;
+const GTP_FLAG_PT: u8 = 0x10;
+const GTP_FLAG_EXTENSION: u8 = 0x04;
+// any of the extension, sequence number or N-PDU number flags
+const GTP_FLAGS_OPTIONAL: u8 = 0x07;
+const GTP_MSG_GPDU: u8 = 0xff;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TunnelKind {
+  Gre,
+  Vxlan,
+  Gtp,
+  IpInIp,
+}
+
+// The outer endpoints of a tunneled packet
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tunnel {
+  pub kind: TunnelKind,
+  pub saddr: IpAddr,
+  pub daddr: IpAddr,
+  // the GRE key, VXLAN VNI or GTP TEID, 0 if none
+  pub id: u32,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+  ((data[offset] as u16) << 8) | (data[offset + 1] as u16)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+  ((read_u16(data, offset) as u32) << 16) | (read_u16(data, offset + 2) as u32)
+}
+
+/* an inner IP packet has no link layer addresses */
+fn ip_link(data: &[u8]) -> Option<LinkInfo> {
+  ip_by_version(data).map(|ip| LinkInfo {
+    srcmac: MacAddress::nil(),
+    dstmac: MacAddress::nil(),
+    vlan: 0,
+    ip: ip,
+  })
+}
+
+fn parse_gre(payload: &[u8]) -> Option<(u32, LinkInfo)> {
+  if payload.len() < 4 {
+    return None;
+  }
+
+  let flags = read_u16(payload, 0);
+  let proto = read_u16(payload, 2);
+  let mut offset = 4;
+  let mut key = 0;
+
+  // version 1 is PPTP
+  if (flags & GRE_VERSION_MASK) != 0 {
+    return None;
+  }
+
+  if (flags & GRE_CHECKSUM) != 0 {
+    offset += 4;
+  }
+
+  if (flags & GRE_KEY) != 0 {
+    if payload.len() < offset + 4 {
+      return None;
+    }
+
+    key = read_u32(payload, offset);
+    offset += 4;
+  }
+
+  if (flags & GRE_SEQUENCE) != 0 {
+    offset += 4;
+  }
+
+  if payload.len() < offset {
+    return None;
+  }
+
+  let inner = &payload[offset..];
+
+  match proto {
+    GRE_PROTO_IPV4 | GRE_PROTO_IPV6 => ip_link(inner),
+    GRE_PROTO_ETHERNET => decapsulate(DLT_EN10MB, inner),
+    _ => None,
+  }.map(|link| (key, link))
+}
+
+fn parse_vxlan(payload: &[u8]) -> Option<(u32, LinkInfo)> {
+  if (payload.len() < VXLAN_HEADER_LEN) || ((payload[0] & VXLAN_FLAG_VNI) == 0) {
+    return None;
+  }
+
+  let vni = read_u32(payload, 4) >> 8;
+  decapsulate(DLT_EN10MB, &payload[VXLAN_HEADER_LEN..]).map(|link| (vni, link))
+}
+
+/* only the G-PDU messages carry user traffic */
+fn parse_gtp(payload: &[u8]) -> Option<(u32, LinkInfo)> {
+  if payload.len() < GTP_HEADER_LEN {
+    return None;
+  }
+
+  let flags = payload[0];
+
+  if ((flags >> 5) != GTP_VERSION_1) || ((flags & GTP_FLAG_PT) == 0) || (payload[1] != GTP_MSG_GPDU) {
+    return None;
+  }
+
+  let teid = read_u32(payload, 4);
+  let mut offset = GTP_HEADER_LEN;
+
+  if (flags & GTP_FLAGS_OPTIONAL) != 0 {
+    offset += GTP_OPTIONAL_LEN;
+
+    if payload.len() < offset {
+      return None;
+    }
+
+    // the next extension header type is the last optional byte
+    let mut next_extension = if (flags & GTP_FLAG_EXTENSION) != 0 { payload[offset - 1] } else { 0 };
+
+    while next_extension != 0 {
+      // the length is in 4 bytes units, the last byte is the next extension type
+      let len = match payload.get(offset) {
+        Some(&len) if len > 0 => (len as usize) * 4,
+        _ => return None,
+      };
+
+      if payload.len() < offset + len {
+        return None;
+      }
+
+      next_extension = payload[offset + len - 1];
+      offset += len;
+    }
+  }
+
+  if payload.len() < offset {
+    return None;
+  }
+
+  ip_link(&payload[offset..]).map(|link| (teid, link))
+}
+
+/* the tunnel and the inner packet, if outer is a supported tunnel.
+ * payload is the outer transport payload. VXLAN and GTP-U are only matched by
+ * the destination port: their source port is chosen by the sender */
+pub fn decapsulate_tunnel<'a>(outer: &PacketTuple, payload: &'a [u8]) -> Option<(Tunnel, LinkInfo<'a>)> {
+  let (kind, inner) = match outer.proto {
+    IPPROTO_IPIP | IPPROTO_IPV6 => (TunnelKind::IpInIp, ip_link(payload).map(|link| (0, link))),
+    IPPROTO_GRE => (TunnelKind::Gre, parse_gre(payload)),
+    IPPROTO_UDP if outer.dport == VXLAN_PORT => (TunnelKind::Vxlan, parse_vxlan(payload)),
+    IPPROTO_UDP if outer.dport == GTP_U_PORT => (TunnelKind::Gtp, parse_gtp(payload)),
+    _ => return None,
+  };
+
+  inner.map(|(id, link)| {
+    (Tunnel {
+      kind: kind,
+      saddr: outer.saddr,
+      daddr: outer.daddr,
+      id: id,
+    }, link)
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const INNER_IP: &[u8] = &[
+    0x45, 0, 0, 20, 0, 0, 0, 0, 64, IPPROTO_UDP, 0, 0,
+    10, 0, 0, 1, 10, 0, 0, 2,
+  ];
+
+  fn outer(proto: u8, sport: u16, dport: u16) -> PacketTuple {
+    PacketTuple {
+      vlan: 0,
+      proto: proto,
+      saddr: Ipv4Addr::new(192, 168, 0, 1).into(),
+      daddr: Ipv4Addr::new(192, 168, 0, 2).into(),
+      sport: sport,
+      dport: dport,
+    }
+  }
+
+  fn with_inner_ip(header: &[u8]) -> Vec<u8> {
+    let mut payload = header.to_vec();
+    payload.extend_from_slice(INNER_IP);
+    payload
+  }
+
+  #[test]
+  fn gre_with_checksum_key_and_sequence() {
+    let payload = with_inner_ip(&[
+      0xb0, 0x00, 0x08, 0x00,
+      // checksum and reserved
+      0x12, 0x34, 0x00, 0x00,
+      // key
+      0x00, 0x00, 0x04, 0xd2,
+      // sequence number
+      0x00, 0x00, 0x00, 0x07,
+    ]);
+    let (tunnel, link) = decapsulate_tunnel(&outer(IPPROTO_GRE, 0, 0), &payload).unwrap();
+
+    assert_eq!(tunnel.kind, TunnelKind::Gre);
+    assert_eq!(tunnel.id, 1234);
+    assert_eq!(link.ip, INNER_IP);
+  }
+
+  #[test]
+  fn gre_truncated_options() {
+    // the key flag is set, but the key is missing
+    let payload = [0x20, 0x00, 0x08, 0x00, 0x00, 0x00];
+    assert!(decapsulate_tunnel(&outer(IPPROTO_GRE, 0, 0), &payload).is_none());
+  }
+
+  #[test]
+  fn gtp_extension_headers() {
+    let payload = with_inner_ip(&[
+      // version 1, PT, extension header flag, G-PDU
+      0x34, 0xff, 0x00, 0x00,
+      // TEID
+      0x00, 0x00, 0x00, 0x2a,
+      // sequence number, N-PDU number, next extension type
+      0x00, 0x00, 0x00, 0x85,
+      // a 4 bytes extension, the last one
+      0x01, 0x10, 0x09, 0x00,
+    ]);
+    let (tunnel, link) = decapsulate_tunnel(&outer(IPPROTO_UDP, 40000, GTP_U_PORT), &payload).unwrap();
+
+    assert_eq!(tunnel.kind, TunnelKind::Gtp);
+    assert_eq!(tunnel.id, 42);
+    assert_eq!(link.ip, INNER_IP);
+  }
+
+  #[test]
+  fn gtp_truncated_extension_chain() {
+    let payload = [
+      0x34, 0xff, 0x00, 0x00,
+      0x00, 0x00, 0x00, 0x2a,
+      0x00, 0x00, 0x00, 0x85,
+      // an 8 bytes extension, with only 3 bytes left
+      0x02, 0x10, 0x09,
+    ];
+    assert!(decapsulate_tunnel(&outer(IPPROTO_UDP, 40000, GTP_U_PORT), &payload).is_none());
+
+    // a zero length extension would never end
+    let payload = [
+      0x34, 0xff, 0x00, 0x00,
+      0x00, 0x00, 0x00, 0x2a,
+      0x00, 0x00, 0x00, 0x85,
+      0x00, 0x00, 0x00, 0x00,
+    ];
+    assert!(decapsulate_tunnel(&outer(IPPROTO_UDP, 40000, GTP_U_PORT), &payload).is_none());
+  }
+
+  #[test]
+  fn vxlan() {
+    let mut payload = vec![
+      // the I flag, VNI 5000
+      0x08, 0x00, 0x00, 0x00, 0x00, 0x13, 0x88, 0x00,
+      // inner Ethernet
+      0x02, 0x00, 0x00, 0x00, 0x00, 0x02,
+      0x02, 0x00, 0x00, 0x00, 0x00, 0x01,
+      0x08, 0x00,
+    ];
+    payload.extend_from_slice(INNER_IP);
+
+    let (tunnel, link) = decapsulate_tunnel(&outer(IPPROTO_UDP, 50000, VXLAN_PORT), &payload).unwrap();
+    assert_eq!(tunnel.kind, TunnelKind::Vxlan);
+    assert_eq!(tunnel.id, 5000);
+    assert_eq!(link.ip, INNER_IP);
+    assert_eq!(link.srcmac, MacAddress::new([0x02, 0, 0, 0, 0, 0x01]));
+
+    // without the I flag the VNI is not valid
+    payload[0] = 0x00;
+    assert!(decapsulate_tunnel(&outer(IPPROTO_UDP, 50000, VXLAN_PORT), &payload).is_none());
+  }
+
+  #[test]
+  fn ip_in_ip() {
+    let (tunnel, link) = decapsulate_tunnel(&outer(IPPROTO_IPIP, 0, 0), INNER_IP).unwrap();
+
+    assert_eq!(tunnel.kind, TunnelKind::IpInIp);
+    assert_eq!(tunnel.id, 0);
+    assert_eq!(tunnel.saddr, IpAddr::from(Ipv4Addr::new(192, 168, 0, 1)));
+    assert_eq!(link.ip, INNER_IP);
+  }
+}
@@ -1,14 +1,38 @@
+use std::cmp;
 use std::convert;
+use std::fmt;
 use std::ops::Sub;
+use std::str::FromStr;
 use libc::timeval as libc_timeval;
 
 pub type Duration = ::std::time::Duration;
 pub type SystemTime = ::std::time::SystemTime;
+pub type IpAddr = ::std::net::IpAddr;
 pub type Ipv4Addr = ::std::net::Ipv4Addr;
+pub type Ipv6Addr = ::std::net::Ipv6Addr;
 pub type MacAddress = ::eui48::MacAddress;
-pub type ManagedPtr<T> = super::managed_ptr::ManagedPtr<T>;
+// Reference counted: items are freed when no table or flow references them
+pub type Shared<T> = ::std::rc::Rc<::std::cell::RefCell<T>>;
 pub const UNIX_EPOCH: SystemTime = ::std::time::UNIX_EPOCH;
 
+pub const IPPROTO_ICMP: u8 = 1;
+pub const IPPROTO_TCP: u8 = 6;
+pub const IPPROTO_UDP: u8 = 17;
+pub const IPPROTO_GRE: u8 = 47;
+pub const IPPROTO_ESP: u8 = 50;
+pub const IPPROTO_ICMPV6: u8 = 58;
+pub const IPPROTO_SCTP: u8 = 132;
+
+/* the protocols whose flows are keyed by ports. ICMP flows use the identifier
+ * or the type and code instead, see PacketTuple */
+pub fn has_ports(proto: u8) -> bool {
+  (proto == IPPROTO_TCP) || (proto == IPPROTO_UDP) || (proto == IPPROTO_SCTP)
+}
+
+pub fn is_icmp(proto: u8) -> bool {
+  (proto == IPPROTO_ICMP) || (proto == IPPROTO_ICMPV6)
+}
+
 pub fn u32_to_ipv4(val: u32) -> Ipv4Addr {
   Ipv4Addr::new(
     (val >> 24) as u8 & 0xFF,
@@ -17,6 +41,14 @@ pub fn u32_to_ipv4(val: u32) -> Ipv4Addr {
     (val) as u8 & 0xFF)
 }
 
+/* nDPI IP based guessing only works with IPv4 addresses, 0 otherwise */
+pub fn ip_to_u32(val: &IpAddr) -> u32 {
+  match val {
+    IpAddr::V4(ip) => (*ip).into(),
+    IpAddr::V6(_) => 0,
+  }
+}
+
 #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
 pub struct Timeval {
   pub sec: i64,
@@ -43,6 +75,17 @@ impl convert::From<SystemTime> for Timeval {
   }
 }
 
+impl Timeval {
+  pub fn add_secs(&self, secs: f64) -> Timeval {
+    let usec = self.usec + (secs.fract() * 1000_000_f64) as i64;
+
+    Timeval {
+      sec: self.sec + secs.trunc() as i64 + usec / 1000_000,
+      usec: usec % 1000_000,
+    }
+  }
+}
+
 impl Sub for Timeval {
   type Output = f64;
 
@@ -51,6 +94,70 @@ impl Sub for Timeval {
   }
 }
 
+// An IPv4 or IPv6 network in CIDR notation, e.g. 10.0.0.0/8
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct IpNetwork {
+  pub addr: IpAddr,
+  pub prefix: u8,
+}
+
+impl IpNetwork {
+  /* the network of the given prefix containing addr */
+  pub fn from_addr(addr: &IpAddr, prefix: u8) -> IpNetwork {
+    match addr {
+      IpAddr::V4(ip) => {
+        let prefix = cmp::min(prefix, 32);
+        let mask = if prefix == 0 { 0 } else { !0u32 << (32 - prefix) };
+        IpNetwork { addr: Ipv4Addr::from(u32::from(*ip) & mask).into(), prefix: prefix }
+      },
+      IpAddr::V6(ip) => {
+        let prefix = cmp::min(prefix, 128);
+        let mask = if prefix == 0 { 0 } else { !0u128 << (128 - prefix) };
+        IpNetwork { addr: Ipv6Addr::from(u128::from(*ip) & mask).into(), prefix: prefix }
+      },
+    }
+  }
+
+  pub fn contains(&self, ip: &IpAddr) -> bool {
+    match (self.addr, ip) {
+      (IpAddr::V4(net), IpAddr::V4(ip)) => {
+        let mask = if self.prefix == 0 { 0 } else { !0u32 << (32 - self.prefix) };
+        (u32::from(net) & mask) == (u32::from(*ip) & mask)
+      },
+      (IpAddr::V6(net), IpAddr::V6(ip)) => {
+        let mask = if self.prefix == 0 { 0 } else { !0u128 << (128 - self.prefix) };
+        (u128::from(net) & mask) == (u128::from(*ip) & mask)
+      },
+      _ => false,
+    }
+  }
+}
+
+impl FromStr for IpNetwork {
+  type Err = String;
+
+  /* a plain address is a network with the maximum prefix */
+  fn from_str(s: &str) -> Result<IpNetwork, String> {
+    let mut parts = s.splitn(2, '/');
+    let addr: IpAddr = parts.next().unwrap().parse()
+      .map_err(|_| format!("Invalid address: {}", s))?;
+    let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = match parts.next() {
+      Some(prefix) => prefix.parse::<u8>().ok().filter(|p| *p <= max_prefix)
+        .ok_or(format!("Invalid prefix: {}", s))?,
+      None => max_prefix,
+    };
+
+    Ok(IpNetwork { addr: addr, prefix: prefix })
+  }
+}
+
+impl fmt::Display for IpNetwork {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}/{}", self.addr, self.prefix)
+  }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum PacketDir {
   Src2Dst,
@@ -66,28 +173,205 @@ impl PacketDir {
   }
 }
 
-#[derive(Debug)]
+// The columns top_flows can sort by
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FlowSort {
+  Throughput,
+  // source to destination
+  TxThroughput,
+  // destination to source
+  RxThroughput,
+  // smoothed throughput, see TrafficStats
+  RateShort,
+  RateMedium,
+  RateLong,
+  PeakThroughput,
+  Bytes,
+  Packets,
+  Protocol,
+  Age,
+  Source,
+  Destination,
+}
+
+impl FlowSort {
+  /* the next sort column, to cycle through all of them */
+  pub fn next(&self) -> FlowSort {
+    match self {
+      FlowSort::Throughput => FlowSort::TxThroughput,
+      FlowSort::TxThroughput => FlowSort::RxThroughput,
+      FlowSort::RxThroughput => FlowSort::RateShort,
+      FlowSort::RateShort => FlowSort::RateMedium,
+      FlowSort::RateMedium => FlowSort::RateLong,
+      FlowSort::RateLong => FlowSort::PeakThroughput,
+      FlowSort::PeakThroughput => FlowSort::Bytes,
+      FlowSort::Bytes => FlowSort::Packets,
+      FlowSort::Packets => FlowSort::Protocol,
+      FlowSort::Protocol => FlowSort::Age,
+      FlowSort::Age => FlowSort::Source,
+      FlowSort::Source => FlowSort::Destination,
+      FlowSort::Destination => FlowSort::Throughput,
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug)]
 pub enum L4Proto {
   TCP,
   UDP,
   ICMP,
+  ICMPV6,
+  GRE,
+  ESP,
+  SCTP,
 
   UNKNOWN
 }
 
-#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+// For ICMP echo request/reply, sport and dport are both the echo identifier,
+// so that the replies join the requests flow. For other ICMP messages sport
+// is 0 and dport is type << 8 | code. Protocols without ports have them 0.
+// The VLAN ID (0 if untagged) separates overlapping address spaces.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub struct PacketTuple {
+  pub vlan: u16,
   pub proto: u8,
-  pub saddr: u32,
-  pub daddr: u32,
+  pub saddr: IpAddr,
+  pub daddr: IpAddr,
   pub sport: u16,
   pub dport: u16
 }
 
+impl Default for PacketTuple {
+  fn default() -> PacketTuple {
+    PacketTuple {
+      vlan: 0,
+      proto: 0,
+      saddr: Ipv4Addr::UNSPECIFIED.into(),
+      daddr: Ipv4Addr::UNSPECIFIED.into(),
+      sport: 0,
+      dport: 0,
+    }
+  }
+}
+
+// Hosts are identified by address and VLAN ID
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub struct HostKey {
+  pub vlan: u16,
+  pub ip: IpAddr,
+}
+
 impl PacketTuple {
   pub fn ok(&self) -> bool {
     return (self.proto != 0)
-      && (self.saddr!=0) && (self.daddr != 0)
-      && (self.sport != 0) && (self.dport!=0);
+      && !self.saddr.is_unspecified() && !self.daddr.is_unspecified()
+      && (!has_ports(self.proto) || ((self.sport != 0) && (self.dport != 0)));
+  }
+
+  /* the transport ports, 0 for the protocols without ports, e.g. the ICMP
+   * identifier or type and code are not ports */
+  pub fn ports(&self) -> (u16, u16) {
+    if has_ports(self.proto) { (self.sport, self.dport) } else { (0, 0) }
+  }
+
+  pub fn reversed(&self) -> PacketTuple {
+    PacketTuple {
+      vlan: self.vlan,
+      proto: self.proto,
+      saddr: self.daddr,
+      daddr: self.saddr,
+      sport: self.dport,
+      dport: self.sport,
+    }
+  }
+
+  pub fn src_host(&self) -> HostKey {
+    HostKey { vlan: self.vlan, ip: self.saddr }
+  }
+
+  pub fn dst_host(&self) -> HostKey {
+    HostKey { vlan: self.vlan, ip: self.daddr }
+  }
+
+  /* direction independent key: the lower (addr, port) endpoint always comes first */
+  pub fn key(&self) -> PacketTuple {
+    if (self.saddr, self.sport) <= (self.daddr, self.dport) { *self } else { self.reversed() }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn network(addr: &str, prefix: u8) -> IpNetwork {
+    IpNetwork::from_addr(&addr.parse().unwrap(), prefix)
+  }
+
+  #[test]
+  fn network_from_ipv4_addr() {
+    assert_eq!(network("192.168.10.20", 24).to_string(), "192.168.10.0/24");
+    assert_eq!(network("192.168.10.20", 0).to_string(), "0.0.0.0/0");
+    assert_eq!(network("192.168.10.20", 32).to_string(), "192.168.10.20/32");
+    // clamped to the address length
+    assert_eq!(network("192.168.10.20", 64).to_string(), "192.168.10.20/32");
+  }
+
+  #[test]
+  fn network_from_ipv6_addr() {
+    assert_eq!(network("2001:db8:1:2:3:4:5:6", 64).to_string(), "2001:db8:1:2::/64");
+    assert_eq!(network("2001:db8:1:2:3:4:5:6", 0).to_string(), "::/0");
+    assert_eq!(network("2001:db8:1:2:3:4:5:6", 128).to_string(), "2001:db8:1:2:3:4:5:6/128");
+  }
+
+  #[test]
+  fn network_contains_its_addresses() {
+    let addr: IpAddr = "10.1.2.3".parse().unwrap();
+
+    for prefix in &[0, 8, 31, 32] {
+      assert!(network("10.1.2.3", *prefix).contains(&addr));
+    }
+
+    assert!(!network("10.1.2.3", 32).contains(&"10.1.2.4".parse().unwrap()));
+    assert!(!network("10.1.2.3", 0).contains(&"::1".parse().unwrap()));
+  }
+
+  fn udp_tuple() -> PacketTuple {
+    PacketTuple {
+      vlan: 0,
+      proto: IPPROTO_UDP,
+      saddr: "10.0.0.1".parse().unwrap(),
+      daddr: "10.0.0.2".parse().unwrap(),
+      sport: 1000,
+      dport: 53,
+    }
+  }
+
+  #[test]
+  fn tuple_ok() {
+    let tuple = udp_tuple();
+    assert!(tuple.ok());
+
+    // the protocols with ports need both
+    assert!(!PacketTuple { sport: 0, ..tuple }.ok());
+    assert!(!PacketTuple { proto: IPPROTO_SCTP, dport: 0, ..tuple }.ok());
+
+    // the others are tracked by address pair
+    for proto in &[IPPROTO_ICMP, IPPROTO_ICMPV6, IPPROTO_GRE, IPPROTO_ESP, 4] {
+      assert!(PacketTuple { proto: *proto, sport: 0, dport: 0, ..tuple }.ok(), "{}", proto);
+    }
+
+    assert!(!PacketTuple { proto: 0, ..tuple }.ok());
+    assert!(!PacketTuple { daddr: Ipv4Addr::UNSPECIFIED.into(), ..tuple }.ok());
+  }
+
+  #[test]
+  fn tuple_ports() {
+    let tuple = udp_tuple();
+
+    assert_eq!(tuple.ports(), (1000, 53));
+    assert_eq!(PacketTuple { proto: IPPROTO_SCTP, ..tuple }.ports(), (1000, 53));
+    assert_eq!(PacketTuple { proto: IPPROTO_ICMP, sport: 0, dport: 0x0300, ..tuple }.ports(), (0, 0));
+    assert_eq!(PacketTuple { proto: IPPROTO_ICMPV6, sport: 7, dport: 7, ..tuple }.ports(), (0, 0));
   }
 }
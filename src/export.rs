@@ -0,0 +1,198 @@
+use std::io::{self, Write, BufWriter};
+use std::fs::OpenOptions;
+use serde_json::{self, Value};
+
+use engine::types::*;
+use engine::traffic_stats::TrafficStats;
+use engine::snapshot::{Snapshot, FlowInfo, HostInfo, HostGroup};
+use engine::aggregate::{Aggregator, AggregationMode};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum JsonFormat {
+  // one line per flow and per host
+  Ndjson,
+  // one document per export, with the flows, hosts and groups arrays
+  Json,
+}
+
+// Periodically writes the flows, hosts and host groups tables to stdout or a file
+pub struct JsonExporter {
+  out: Box<dyn Write>,
+  to_stdout: bool,
+  format: JsonFormat,
+  // None: only export on exit
+  interval: Option<Duration>,
+  last_export: SystemTime,
+  // the groups are only exported when the hosts are aggregated
+  aggregator: Aggregator,
+}
+
+fn timestamp(tv: &Timeval) -> f64 {
+  (tv.sec as f64) + (tv.usec as f64) / 1000_000.
+}
+
+fn stats_json(stats: &TrafficStats) -> Value {
+  json!({
+    "first_seen": timestamp(&stats.first_seen),
+    "last_seen": timestamp(&stats.last_seen),
+    "src2dst_packets": stats.src2dst_pkts,
+    "dst2src_packets": stats.dst2src_pkts,
+    "src2dst_bytes": stats.src2dst_bytes,
+    "dst2src_bytes": stats.dst2src_bytes,
+    // bytes per second
+    "throughput": stats.throughput,
+    "src2dst_throughput": stats.src2dst_throughput,
+    "dst2src_throughput": stats.dst2src_throughput,
+    "rate_1s": stats.rate_short,
+    "rate_10s": stats.rate_medium,
+    "rate_60s": stats.rate_long,
+    "peak_throughput": stats.peak_throughput,
+  })
+}
+
+fn flow_json(flow: &FlowInfo) -> Value {
+  // the ICMP flows are keyed by the identifier or the type and code instead
+  let (sport, dport) = if has_ports(flow.proto) { (flow.sport, flow.dport) } else { (0, 0) };
+  let (upload_bytes, download_bytes) = flow.up_down_bytes();
+  let (upload_throughput, download_throughput) = flow.up_down_throughput();
+
+  json!({
+    "vlan": flow.vlan,
+    "proto": flow.proto,
+    "src_ip": flow.saddr.to_string(),
+    "src_port": sport,
+    "src_mac": flow.smac.to_hex_string(),
+    "dst_ip": flow.daddr.to_string(),
+    "dst_port": dport,
+    "dst_mac": flow.dmac.to_hex_string(),
+    "direction": format!("{:?}", flow.direction()).to_lowercase(),
+    // from the local side, src2dst and dst2src without a single local side
+    "upload_bytes": upload_bytes,
+    "download_bytes": download_bytes,
+    "upload_throughput": upload_throughput,
+    "download_throughput": download_throughput,
+    "protocol": &*flow.protocol,
+    "master_protocol": &*flow.master_protocol,
+    "app_protocol": &*flow.app_protocol,
+    "detection_completed": flow.detection_completed,
+    "tunnel": flow.tunnel.map(|tunnel| json!({
+      "type": format!("{:?}", tunnel.kind),
+      "src_ip": tunnel.saddr.to_string(),
+      "dst_ip": tunnel.daddr.to_string(),
+      "id": tunnel.id,
+    })),
+    // type << 8 | code of the last messages
+    "icmp": if is_icmp(flow.proto) {
+      json!({ "src2dst_type_code": flow.icmp_type_code.0, "dst2src_type_code": flow.icmp_type_code.1 })
+    } else {
+      Value::Null
+    },
+    "stats": stats_json(&flow.stats),
+  })
+}
+
+fn host_json(host: &HostInfo) -> Value {
+  json!({
+    "ip": host.ip.to_string(),
+    "vlan": host.vlan,
+    "mac": host.mac.to_hex_string(),
+    "local": host.local,
+    "flows": host.num_flows,
+    // src2dst: sent by the host, dst2src: received by the host
+    "stats": stats_json(&host.stats),
+  })
+}
+
+fn group_json(group: &HostGroup) -> Value {
+  json!({
+    "name": group.name,
+    "hosts": group.num_hosts,
+    "flows": group.num_flows,
+    "stats": stats_json(&group.stats),
+  })
+}
+
+impl JsonExporter {
+  /* path "-" is stdout, files are appended to. interval 0 only exports on exit */
+  pub fn new(path: &str, format: JsonFormat, interval: u64, aggregator: Aggregator) -> io::Result<JsonExporter> {
+    let to_stdout = path == "-";
+    let out: Box<dyn Write> = if to_stdout {
+      Box::new(io::stdout())
+    } else {
+      Box::new(BufWriter::new(OpenOptions::new().create(true).append(true).open(path)?))
+    };
+
+    Ok(JsonExporter {
+      out: out,
+      to_stdout: to_stdout,
+      format: format,
+      interval: if interval > 0 { Some(Duration::new(interval, 0)) } else { None },
+      last_export: SystemTime::now(),
+      aggregator: aggregator,
+    })
+  }
+
+  pub fn is_stdout(&self) -> bool {
+    self.to_stdout
+  }
+
+  pub fn export_due(&self) -> bool {
+    match self.interval {
+      Some(interval) => self.last_export.elapsed().unwrap_or_default() >= interval,
+      None => false,
+    }
+  }
+
+  pub fn export(&mut self, snapshot: &Snapshot) -> io::Result<()> {
+    let time = timestamp(&snapshot.when);
+    let groups = if self.aggregator.mode != AggregationMode::Host {
+      self.aggregator.aggregate(snapshot.hosts.iter(), &snapshot.flows)
+    } else {
+      Vec::new()
+    };
+
+    match self.format {
+      JsonFormat::Ndjson => {
+        for flow in snapshot.flows.iter() {
+          let mut record = flow_json(flow);
+          record["type"] = json!("flow");
+          record["time"] = json!(time);
+          writeln!(self.out, "{}", record)?;
+        }
+
+        for host in snapshot.hosts.iter() {
+          let mut record = host_json(host);
+          record["type"] = json!("host");
+          record["time"] = json!(time);
+          writeln!(self.out, "{}", record)?;
+        }
+
+        for group in groups.iter() {
+          let mut record = group_json(group);
+          record["type"] = json!("group");
+          record["mode"] = json!(self.aggregator.mode_name());
+          record["time"] = json!(time);
+          writeln!(self.out, "{}", record)?;
+        }
+      },
+      JsonFormat::Json => {
+        let mut document = json!({
+          "time": time,
+          "flows": snapshot.flows.iter().map(flow_json).collect::<Vec<_>>(),
+          "hosts": snapshot.hosts.iter().map(host_json).collect::<Vec<_>>(),
+        });
+
+        if !groups.is_empty() {
+          document["group_mode"] = json!(self.aggregator.mode_name());
+          document["groups"] = json!(groups.iter().map(group_json).collect::<Vec<_>>());
+        }
+
+        serde_json::to_writer_pretty(&mut self.out, &document)?;
+        writeln!(self.out)?;
+      },
+    }
+
+    self.last_export = SystemTime::now();
+    self.out.flush()
+  }
+}
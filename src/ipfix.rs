@@ -0,0 +1,390 @@
+use std::io;
+use std::net::{UdpSocket, SocketAddr, ToSocketAddrs};
+
+use engine::types::*;
+use engine::flow::{FlowRecord, FlowEndReason};
+
+// IPFIX (RFC 7011) export of the flow records over UDP
+
+const IPFIX_VERSION: u16 = 10;
+const TEMPLATE_SET_ID: u16 = 2;
+const IPV4_TEMPLATE_ID: u16 = 256;
+const IPV6_TEMPLATE_ID: u16 = 257;
+const MESSAGE_HEADER_LEN: usize = 16;
+// to avoid IP fragmentation
+const MAX_MESSAGE_LEN: usize = 1400;
+// over UDP the templates must be periodically resent
+const TEMPLATE_REFRESH_SEC: i64 = 60;
+
+// applicationId: PANA-L7-PEN classification engine (RFC 6759), ntop PEN, nDPI protocol
+const CLASSIFICATION_ENGINE_PANA_L7_PEN: u8 = 20;
+const NTOP_PEN: u32 = 35632;
+const APPLICATION_ID_LEN: u16 = 7;
+
+const IE_OCTET_DELTA_COUNT: u16 = 1;
+const IE_PACKET_DELTA_COUNT: u16 = 2;
+const IE_PROTOCOL_IDENTIFIER: u16 = 4;
+const IE_SOURCE_TRANSPORT_PORT: u16 = 7;
+const IE_SOURCE_IPV4_ADDRESS: u16 = 8;
+const IE_DESTINATION_TRANSPORT_PORT: u16 = 11;
+const IE_DESTINATION_IPV4_ADDRESS: u16 = 12;
+const IE_SOURCE_IPV6_ADDRESS: u16 = 27;
+const IE_DESTINATION_IPV6_ADDRESS: u16 = 28;
+const IE_VLAN_ID: u16 = 58;
+const IE_APPLICATION_ID: u16 = 95;
+const IE_FLOW_END_REASON: u16 = 136;
+const IE_FLOW_START_MILLISECONDS: u16 = 152;
+const IE_FLOW_END_MILLISECONDS: u16 = 153;
+const IE_ICMP_TYPE_CODE_IPV4: u16 = 32;
+const IE_ICMP_TYPE_CODE_IPV6: u16 = 139;
+
+// (information element, length), the addresses and the ICMP type and code come first
+const COMMON_FIELDS: &[(u16, u16)] = &[
+  (IE_SOURCE_TRANSPORT_PORT, 2),
+  (IE_DESTINATION_TRANSPORT_PORT, 2),
+  (IE_PROTOCOL_IDENTIFIER, 1),
+  (IE_VLAN_ID, 2),
+  (IE_OCTET_DELTA_COUNT, 8),
+  (IE_PACKET_DELTA_COUNT, 8),
+  (IE_FLOW_START_MILLISECONDS, 8),
+  (IE_FLOW_END_MILLISECONDS, 8),
+  (IE_FLOW_END_REASON, 1),
+  (IE_APPLICATION_ID, APPLICATION_ID_LEN),
+];
+
+fn put_u8(buf: &mut Vec<u8>, val: u8) {
+  buf.push(val);
+}
+
+fn put_u16(buf: &mut Vec<u8>, val: u16) {
+  buf.push((val >> 8) as u8);
+  buf.push(val as u8);
+}
+
+fn put_u32(buf: &mut Vec<u8>, val: u32) {
+  put_u16(buf, (val >> 16) as u16);
+  put_u16(buf, val as u16);
+}
+
+fn put_u64(buf: &mut Vec<u8>, val: u64) {
+  put_u32(buf, (val >> 32) as u32);
+  put_u32(buf, val as u32);
+}
+
+fn set_u16(buf: &mut Vec<u8>, offset: usize, val: u16) {
+  buf[offset] = (val >> 8) as u8;
+  buf[offset + 1] = val as u8;
+}
+
+fn millis(tv: &Timeval) -> u64 {
+  (tv.sec as u64) * 1000 + (tv.usec as u64) / 1000
+}
+
+fn end_reason_code(reason: FlowEndReason) -> u8 {
+  match reason {
+    FlowEndReason::IdleTimeout => 1,
+    FlowEndReason::ActiveTimeout => 2,
+    FlowEndReason::ForcedEnd => 4,
+  }
+}
+
+fn put_template(buf: &mut Vec<u8>, template_id: u16, saddr_ie: u16, daddr_ie: u16, addr_len: u16, icmp_ie: u16) {
+  put_u16(buf, template_id);
+  put_u16(buf, (COMMON_FIELDS.len() + 3) as u16);
+  put_u16(buf, saddr_ie);
+  put_u16(buf, addr_len);
+  put_u16(buf, daddr_ie);
+  put_u16(buf, addr_len);
+  put_u16(buf, icmp_ie);
+  put_u16(buf, 2);
+
+  for &(ie, len) in COMMON_FIELDS {
+    put_u16(buf, ie);
+    put_u16(buf, len);
+  }
+}
+
+fn put_addr(buf: &mut Vec<u8>, addr: &IpAddr) {
+  match addr {
+    IpAddr::V4(addr) => buf.extend_from_slice(&addr.octets()),
+    IpAddr::V6(addr) => buf.extend_from_slice(&addr.octets()),
+  }
+}
+
+/* a data record for a single direction of the flow, IPFIX records are unidirectional.
+ * The ICMP flows have no ports, the type and code are exported instead */
+fn put_record(buf: &mut Vec<u8>, record: &FlowRecord, src2dst: bool) {
+  let tuple = &record.tuple;
+  let (sport, dport) = tuple.ports();
+  let (saddr, daddr, sport, dport, pkts, bytes, icmp_type_code) = if src2dst {
+    (&tuple.saddr, &tuple.daddr, sport, dport, record.src2dst_pkts, record.src2dst_bytes, record.icmp_type_code.0)
+  } else {
+    (&tuple.daddr, &tuple.saddr, dport, sport, record.dst2src_pkts, record.dst2src_bytes, record.icmp_type_code.1)
+  };
+
+  put_addr(buf, saddr);
+  put_addr(buf, daddr);
+  put_u16(buf, icmp_type_code);
+  put_u16(buf, sport);
+  put_u16(buf, dport);
+  put_u8(buf, tuple.proto);
+  put_u16(buf, tuple.vlan);
+  put_u64(buf, bytes);
+  put_u64(buf, pkts as u64);
+  put_u64(buf, millis(&record.start));
+  put_u64(buf, millis(&record.end));
+  put_u8(buf, end_reason_code(record.end_reason));
+  put_u8(buf, CLASSIFICATION_ENGINE_PANA_L7_PEN);
+  put_u32(buf, NTOP_PEN);
+  put_u16(buf, record.app_protocol);
+}
+
+pub struct IpfixExporter {
+  socket: UdpSocket,
+  observation_domain: u32,
+  // number of data records sent
+  sequence: u32,
+  last_templates: Option<Timeval>,
+}
+
+impl IpfixExporter {
+  /* each exporter must have its own observation domain, as sequence numbers are per domain */
+  pub fn new(collector: &str, observation_domain: u32) -> io::Result<IpfixExporter> {
+    let addr: SocketAddr = collector.to_socket_addrs()?.next().ok_or_else(|| {
+      io::Error::new(io::ErrorKind::InvalidInput, format!("Cannot resolve {}", collector))
+    })?;
+    let socket = match addr {
+      SocketAddr::V4(_) => UdpSocket::bind("0.0.0.0:0")?,
+      SocketAddr::V6(_) => UdpSocket::bind("[::]:0")?,
+    };
+
+    socket.connect(addr)?;
+
+    Ok(IpfixExporter {
+      socket: socket,
+      observation_domain: observation_domain,
+      sequence: 0,
+      last_templates: None,
+    })
+  }
+
+  fn new_message(&self) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(MAX_MESSAGE_LEN);
+
+    // the length and export time are set on send
+    put_u16(&mut buf, IPFIX_VERSION);
+    put_u16(&mut buf, 0);
+    put_u32(&mut buf, 0);
+    put_u32(&mut buf, self.sequence);
+    put_u32(&mut buf, self.observation_domain);
+    buf
+  }
+
+  fn send_message(&self, mut buf: Vec<u8>, now: &Timeval) -> io::Result<()> {
+    let len = buf.len() as u16;
+    let export_time = now.sec as u32;
+
+    set_u16(&mut buf, 2, len);
+    buf[4] = (export_time >> 24) as u8;
+    buf[5] = (export_time >> 16) as u8;
+    buf[6] = (export_time >> 8) as u8;
+    buf[7] = export_time as u8;
+
+    self.socket.send(&buf).map(|_| ())
+  }
+
+  fn send_templates(&mut self, now: &Timeval) -> io::Result<()> {
+    let mut buf = self.new_message();
+
+    put_u16(&mut buf, TEMPLATE_SET_ID);
+    put_u16(&mut buf, 0);
+    put_template(&mut buf, IPV4_TEMPLATE_ID, IE_SOURCE_IPV4_ADDRESS, IE_DESTINATION_IPV4_ADDRESS, 4,
+      IE_ICMP_TYPE_CODE_IPV4);
+    put_template(&mut buf, IPV6_TEMPLATE_ID, IE_SOURCE_IPV6_ADDRESS, IE_DESTINATION_IPV6_ADDRESS, 16,
+      IE_ICMP_TYPE_CODE_IPV6);
+
+    let set_len = (buf.len() - MESSAGE_HEADER_LEN) as u16;
+    set_u16(&mut buf, MESSAGE_HEADER_LEN + 2, set_len);
+
+    self.send_message(buf, now)?;
+    self.last_templates = Some(*now);
+    Ok(())
+  }
+
+  /* sends the records of a template in as few messages as possible */
+  fn send_data(&mut self, template_id: u16, records: &[&FlowRecord], now: &Timeval) -> io::Result<()> {
+    let mut buf = Vec::new();
+
+    for record in records {
+      for &src2dst in &[true, false] {
+        let pkts = if src2dst { record.src2dst_pkts } else { record.dst2src_pkts };
+
+        if pkts == 0 {
+          continue;
+        }
+
+        if buf.is_empty() {
+          buf = self.new_message();
+          put_u16(&mut buf, template_id);
+          put_u16(&mut buf, 0);
+        }
+
+        put_record(&mut buf, record, src2dst);
+        self.sequence = self.sequence.wrapping_add(1);
+
+        // leave room for the biggest record
+        if buf.len() + 128 > MAX_MESSAGE_LEN {
+          self.flush_data(&mut buf, now)?;
+        }
+      }
+    }
+
+    self.flush_data(&mut buf, now)
+  }
+
+  fn flush_data(&self, buf: &mut Vec<u8>, now: &Timeval) -> io::Result<()> {
+    if buf.is_empty() {
+      return Ok(());
+    }
+
+    let set_len = (buf.len() - MESSAGE_HEADER_LEN) as u16;
+    let mut message = Vec::new();
+
+    ::std::mem::swap(&mut message, buf);
+    set_u16(&mut message, MESSAGE_HEADER_LEN + 2, set_len);
+    self.send_message(message, now)
+  }
+
+  pub fn export(&mut self, records: &[FlowRecord], now: &Timeval) -> io::Result<()> {
+    if records.is_empty() {
+      return Ok(());
+    }
+
+    let refresh = match self.last_templates {
+      Some(last) => (now.sec - last.sec) >= TEMPLATE_REFRESH_SEC,
+      None => true,
+    };
+
+    if refresh {
+      self.send_templates(now)?;
+    }
+
+    let (v4, v6): (Vec<&FlowRecord>, Vec<&FlowRecord>) = records.iter().partition(|record| record.tuple.saddr.is_ipv4());
+
+    self.send_data(IPV4_TEMPLATE_ID, &v4, now)?;
+    self.send_data(IPV6_TEMPLATE_ID, &v6, now)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // addresses, ICMP type and code, ports, protocol, VLAN, counters, timestamps, end reason, applicationId
+  const IPV4_RECORD_LEN: usize = 4 + 4 + 2 + 2 + 2 + 1 + 2 + 8 + 8 + 8 + 8 + 1 + APPLICATION_ID_LEN as usize;
+
+  fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    ((buf[offset] as u16) << 8) | (buf[offset + 1] as u16)
+  }
+
+  fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    ((read_u16(buf, offset) as u32) << 16) | (read_u16(buf, offset + 2) as u32)
+  }
+
+  fn icmp_record(src2dst_pkts: u32, dst2src_pkts: u32) -> FlowRecord {
+    // an echo flow, keyed by the identifier
+    FlowRecord {
+      tuple: PacketTuple { proto: IPPROTO_ICMP, sport: 0x1234, dport: 0x1234, ..record(0, 0, 0).tuple },
+      // echo request, echo reply
+      icmp_type_code: (0x0800, 0x0000),
+      ..record(0, src2dst_pkts, dst2src_pkts)
+    }
+  }
+
+  fn record(sport: u16, src2dst_pkts: u32, dst2src_pkts: u32) -> FlowRecord {
+    FlowRecord {
+      tuple: PacketTuple {
+        vlan: 0,
+        proto: IPPROTO_TCP,
+        saddr: Ipv4Addr::new(10, 0, 0, 1).into(),
+        daddr: Ipv4Addr::new(10, 0, 0, 2).into(),
+        sport: sport,
+        dport: 443,
+      },
+      start: Timeval { sec: 100, usec: 0 },
+      end: Timeval { sec: 110, usec: 0 },
+      src2dst_pkts: src2dst_pkts,
+      dst2src_pkts: dst2src_pkts,
+      src2dst_bytes: (src2dst_pkts as u64) * 100,
+      dst2src_bytes: (dst2src_pkts as u64) * 100,
+      app_protocol: 7,
+      end_reason: FlowEndReason::IdleTimeout,
+      icmp_type_code: (0, 0),
+    }
+  }
+
+  /* checks the message header, returns the message and the ID of its only set */
+  fn receive(collector: &UdpSocket, sequence: u32) -> (Vec<u8>, u16) {
+    let mut buf = [0u8; 2048];
+    let len = collector.recv(&mut buf).unwrap();
+    let message = buf[..len].to_vec();
+
+    assert_eq!(read_u16(&message, 0), IPFIX_VERSION);
+    assert_eq!(read_u16(&message, 2) as usize, len);
+    assert_eq!(read_u32(&message, 4), 120);
+    assert_eq!(read_u32(&message, 8), sequence);
+    assert_eq!(read_u32(&message, 12), 7);
+    assert_eq!(read_u16(&message, MESSAGE_HEADER_LEN + 2) as usize, len - MESSAGE_HEADER_LEN);
+
+    let set_id = read_u16(&message, MESSAGE_HEADER_LEN);
+    (message, set_id)
+  }
+
+  #[test]
+  fn exports_templates_and_records() {
+    let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+    collector.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+    let mut exporter = IpfixExporter::new(&collector.local_addr().unwrap().to_string(), 7).unwrap();
+    let now = Timeval { sec: 120, usec: 0 };
+
+    // a record per direction with traffic
+    exporter.export(&[record(1000, 2, 1), record(1001, 1, 0)], &now).unwrap();
+
+    let (_, set_id) = receive(&collector, 0);
+    assert_eq!(set_id, TEMPLATE_SET_ID);
+
+    let (message, set_id) = receive(&collector, 0);
+    assert_eq!(set_id, IPV4_TEMPLATE_ID);
+    assert_eq!(message.len(), MESSAGE_HEADER_LEN + 4 + 3 * IPV4_RECORD_LEN);
+
+    // the templates are not resent before the refresh, the sequence counts the records
+    exporter.export(&[record(1002, 1, 1)], &now).unwrap();
+
+    let (message, set_id) = receive(&collector, 3);
+    assert_eq!(set_id, IPV4_TEMPLATE_ID);
+    assert_eq!(message.len(), MESSAGE_HEADER_LEN + 4 + 2 * IPV4_RECORD_LEN);
+  }
+
+  #[test]
+  fn icmp_records() {
+    let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+    collector.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+    let mut exporter = IpfixExporter::new(&collector.local_addr().unwrap().to_string(), 7).unwrap();
+    exporter.export(&[icmp_record(1, 1)], &Timeval { sec: 120, usec: 0 }).unwrap();
+    receive(&collector, 0);
+
+    let (message, _) = receive(&collector, 0);
+    assert_eq!(message.len(), MESSAGE_HEADER_LEN + 4 + 2 * IPV4_RECORD_LEN);
+
+    // the type and code follow the addresses, then the ports
+    let request = MESSAGE_HEADER_LEN + 4 + 8;
+    let reply = request + IPV4_RECORD_LEN;
+    assert_eq!(read_u16(&message, request), 0x0800);
+    assert_eq!(read_u32(&message, request + 2), 0);
+    assert_eq!(message[request + 6], IPPROTO_ICMP);
+    assert_eq!(read_u16(&message, reply), 0x0000);
+    assert_eq!(read_u32(&message, reply + 2), 0);
+  }
+}
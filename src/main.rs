@@ -8,29 +8,39 @@ extern crate etherparse;
 extern crate simple_logger;
 extern crate termion;
 extern crate tui;
+extern crate clap;
+#[macro_use] extern crate serde_json;
 
 #[macro_use] extern crate log;
 
 use pcap::Device;
-use pcap::Capture;
+use std::thread;
+use std::io::Write;
+use std::sync::atomic::Ordering;
 
 mod engine;
 mod ui;
+mod config;
+mod capture;
+mod report;
+mod pipeline;
+mod export;
+mod ipfix;
+mod metrics;
 
 use engine::types::*;
-use engine::packet_handler::*;
+use engine::snapshot::Snapshot;
+use engine::aggregate::Aggregator;
 use ui::*;
+use config::Config;
+use pipeline::{Pipeline, CaptureCounters};
+use report::{write_report, write_header};
+use export::JsonExporter;
 use termion::input::TermRead;
-use log::{LevelFilter, set_max_level};
-use std::io::Read;
+use log::set_max_level;
 use termion::async_stdin;
 
-const SNAPLEN: i32 = 0;
-const PROMISC: bool = true;
-const PACKET_TIMEOUT_MS: i32 = 10;
-const PURGE_TIMEOUT_SEC: u64 = 3;
-const STATS_TIMEOUT_SEC: u64 = 5;
-const REDRAW_TIMEOUT_SEC: u64 = 3;
+const INPUT_POLL_MS: u64 = 50;
 
 impl std::convert::From<u8> for L4Proto {
   fn from(proto: u8) -> L4Proto {
@@ -38,62 +48,214 @@ impl std::convert::From<u8> for L4Proto {
       1 => L4Proto::ICMP,
       6 => L4Proto::TCP,
       17 => L4Proto::UDP,
+      47 => L4Proto::GRE,
+      50 => L4Proto::ESP,
+      58 => L4Proto::ICMPV6,
+      132 => L4Proto::SCTP,
       _ => L4Proto::UNKNOWN,
     }
   }
 }
 
+fn list_devices() {
+  for device in Device::list().unwrap() {
+    match device.desc {
+      Some(desc) => println!("{}\t{}", device.name, desc),
+      None => println!("{}", device.name),
+    }
+  }
+}
+
+/* e.g. a packet processing thread failure */
+fn exit_with_error(err: String) -> ! {
+  eprintln!("{}", err);
+  std::process::exit(1);
+}
+
+/* the JSON export to stdout replaces the text report, so it is done on every
+ * report. To a file, it follows the export interval, and last is always exported */
+fn print_batch_report(config: &Config, snapshot: &Snapshot, counters: &CaptureCounters, aggregator: &Aggregator,
+    exporter: &mut Option<JsonExporter>, last: bool) {
+  let mut stdout = std::io::stdout();
+
+  if let Some(ref mut exporter) = *exporter {
+    if exporter.is_stdout() || last || exporter.export_due() {
+      exporter.export(snapshot).unwrap_or_else(|err| eprintln!("JSON export failed: {}", err));
+    }
+
+    if exporter.is_stdout() {
+      return;
+    }
+  }
+
+  write_header(&mut stdout, snapshot, counters.kernel_drops.load(Ordering::Relaxed),
+    counters.queue_drops.load(Ordering::Relaxed)).unwrap();
+  write_report(&mut stdout, snapshot, config.top_n, aggregator).unwrap();
+  writeln!(stdout).unwrap();
+  stdout.flush().unwrap();
+}
+
+/* prints a report every refresh interval, until the iterations or duration
+ * limits are reached or the capture ends */
+fn run_batch(config: &Config, pipeline: Pipeline, aggregator: &Aggregator, exporter: &mut Option<JsonExporter>) {
+  let start = SystemTime::now();
+  let interval = Duration::new(config.redraw_timeout, 0);
+  let duration = Duration::new(config.duration, 0);
+  let mut last_report = start;
+  let mut iterations = 0;
+
+  loop {
+    thread::sleep(Duration::from_millis(INPUT_POLL_MS));
+
+    if pipeline.is_finished() {
+      break;
+    }
+
+    let expired = (config.duration > 0) && (start.elapsed().unwrap_or_default() >= duration);
+
+    if !expired && (last_report.elapsed().unwrap_or_default() < interval) {
+      continue;
+    }
+
+    let snapshot = pipeline.snapshot();
+    let last = expired || ((config.iterations > 0) && (iterations + 1 >= config.iterations));
+    last_report = SystemTime::now();
+    iterations += 1;
+
+    print_batch_report(config, &snapshot, &pipeline.counters, aggregator, exporter, last);
+
+    if last {
+      pipeline.stop().unwrap_or_else(|err| exit_with_error(err));
+      return;
+    }
+  }
+
+  // the capture ended, report the final state
+  let counters = pipeline.counters.clone();
+  let snapshot = pipeline.wait().unwrap_or_else(|err| exit_with_error(err));
+  print_batch_report(config, &snapshot, &counters, aggregator, exporter, true);
+}
+
 fn main() {
-  simple_logger::init().unwrap();
+  let config = Config::from_args();
 
-  let main_device = Device::lookup().unwrap();
-  let mut cap = Capture::from_device(main_device).unwrap()
-    .promisc(PROMISC)
-    .snaplen(SNAPLEN)
-    .timeout(PACKET_TIMEOUT_MS)
-    .immediate_mode(true)
-    .open().unwrap();
+  simple_logger::init().unwrap();
 
   // to set maximum log level
-  set_max_level(LevelFilter::Info);
+  set_max_level(config.log_level);
+
+  if config.list_interfaces {
+    list_devices();
+    return;
+  }
+
+  let aggregator = Aggregator::new(config.aggregation, config.ipv4_prefix, config.ipv6_prefix,
+    config.network_names.clone());
 
-  let mut gui = Ui::new();
-  let mut handler = PacketHandler::new();
-  let mut last_purge = SystemTime::now();
+  let mut exporter = match config.json_output {
+    Some(ref path) => match JsonExporter::new(path, config.json_format, config.json_interval, aggregator.clone()) {
+      Ok(exporter) => Some(exporter),
+      Err(err) => {
+        eprintln!("{}: {}", path, err);
+        std::process::exit(1);
+      },
+    },
+    None => None,
+  };
+
+  let offline_report = config.read_file.is_some() && (config.speed == 0.);
+
+  if !offline_report && !config.batch && exporter.as_ref().map_or(false, |exporter| exporter.is_stdout()) {
+    eprintln!("JSON export to stdout is only possible with --batch or --speed 0, use a file");
+    std::process::exit(1);
+  }
+
+  let pipeline = Pipeline::start(&config).unwrap_or_else(|err| exit_with_error(err));
+
+  if offline_report {
+    let snapshot = pipeline.wait().unwrap_or_else(|err| exit_with_error(err));
+
+    if let Some(ref mut exporter) = exporter {
+      exporter.export(&snapshot).unwrap_or_else(|err| eprintln!("JSON export failed: {}", err));
+
+      // the JSON replaces the report
+      if exporter.is_stdout() {
+        return;
+      }
+    }
+
+    write_report(&mut std::io::stdout(), &snapshot, config.top_n, &aggregator).unwrap();
+    return;
+  }
+
+  if config.batch {
+    run_batch(&config, pipeline, &aggregator, &mut exporter);
+    return;
+  }
+
+  let mut gui = Ui::new(aggregator, !pipeline.local_networks.is_empty());
   let mut last_redraw = SystemTime::now();
-  let mut last_update = SystemTime::now();
-  let purge_timeout = Duration::new(PURGE_TIMEOUT_SEC, 0);
-  let stats_timeout = Duration::new(STATS_TIMEOUT_SEC, 0);
-  let redraw_timeout = Duration::new(REDRAW_TIMEOUT_SEC, 0);
-  let mut first_redraw = true;
+  let redraw_timeout = Duration::new(config.redraw_timeout, 0);
+  let mut redraw_needed = true;
   let mut running = true;
-  let mut stdin = async_stdin().bytes();
+  let mut stdin = async_stdin().keys();
+
+  if let Some(ref filter) = config.filter {
+    gui.set_capture_filter(filter);
+  }
 
   while running {
-    if let Ok(packet) = cap.next() {
-      handler.process_packet(packet.header, packet.data);
+    if pipeline.has_failed() {
+      break;
     }
 
-    let now = SystemTime::now();
-
-    if now.duration_since(last_update).unwrap() >= stats_timeout {
-      handler.update_stats(&now.into());
-      last_update = now;
-    } else if now.duration_since(last_purge).unwrap() >= purge_timeout {
-      handler.purge_idle(&now);
-      last_purge = now;
-    } else if first_redraw || now.duration_since(last_redraw).unwrap() >= redraw_timeout {
-      let top_flows = handler.top_flows();
-      gui.draw(&top_flows, &handler).unwrap();
-      last_redraw = now;
-      first_redraw = false;
-    } else {
-      while let Some(Ok(c)) = stdin.next() {
-        match c {
-          b'q' => { running = false; break},
-          _ => (),
+    while let Some(Ok(key)) = stdin.next() {
+      redraw_needed = true;
+
+      match gui.handle_key(key) {
+        Some(UiEvent::Quit) => { running = false; break },
+        Some(UiEvent::SetCaptureFilter(filter)) => pipeline.set_filter(filter),
+        None => (),
+      }
+    }
+
+    if let Some(result) = pipeline.poll_filter_result() {
+      match result {
+        Ok(filter) => gui.set_capture_filter(&filter),
+        Err(err) => gui.show_message(err),
+      }
+      redraw_needed = true;
+    }
+
+    if let Some(ref mut exporter) = exporter {
+      if exporter.export_due() {
+        if let Err(err) = exporter.export(&pipeline.snapshot()) {
+          gui.show_message(format!("JSON export failed: {}", err));
         }
       }
     }
+
+    if running && (redraw_needed || (last_redraw.elapsed().unwrap_or_default() >= redraw_timeout)) {
+      if !gui.is_paused() {
+        pipeline.watch(gui.watched());
+        gui.update(pipeline.snapshot());
+      }
+
+      gui.set_drops(pipeline.counters.kernel_drops.load(Ordering::Relaxed),
+        pipeline.counters.queue_drops.load(Ordering::Relaxed));
+      gui.draw().unwrap();
+      last_redraw = SystemTime::now();
+      redraw_needed = false;
+    } else {
+      thread::sleep(Duration::from_millis(INPUT_POLL_MS));
+    }
+  }
+
+  // restores the terminal before any error message
+  drop(gui);
+  let snapshot = pipeline.stop().unwrap_or_else(|err| exit_with_error(err));
+
+  if let Some(ref mut exporter) = exporter {
+    exporter.export(&snapshot).unwrap_or_else(|err| eprintln!("JSON export failed: {}", err));
   }
 }
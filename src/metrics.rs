@@ -0,0 +1,222 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::fmt::Write as FmtWrite;
+use std::thread;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+
+use engine::types::*;
+use engine::snapshot::Snapshot;
+use pipeline::{CaptureCounters, lock_shards};
+
+// Prometheus text format metrics, served over HTTP at /metrics
+
+const METRICS_PATH: &str = "/metrics";
+const REQUEST_TIMEOUT_MS: u64 = 1000;
+
+fn escape_label(value: &str) -> String {
+  value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+  let _ = writeln!(out, "# HELP {} {}", name, help);
+  let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/* host metrics are limited to the top_n hosts by bytes, to bound the cardinality */
+fn render(snapshot: &Snapshot, counters: &CaptureCounters, top_n: usize) -> String {
+  let mut out = String::new();
+  let hosts: Vec<_> = snapshot.top_hosts(FlowSort::Bytes, false).into_iter().take(top_n).collect();
+
+  write_header(&mut out, "top_hosts_host_bytes_total", "counter", "Bytes sent and received by the top hosts");
+  for host in hosts.iter() {
+    let labels = format!("host=\"{}\",vlan=\"{}\"", host.ip, host.vlan);
+    let _ = writeln!(out, "top_hosts_host_bytes_total{{{},direction=\"sent\"}} {}", labels, host.stats.src2dst_bytes);
+    let _ = writeln!(out, "top_hosts_host_bytes_total{{{},direction=\"received\"}} {}", labels, host.stats.dst2src_bytes);
+  }
+
+  write_header(&mut out, "top_hosts_host_packets_total", "counter", "Packets sent and received by the top hosts");
+  for host in hosts.iter() {
+    let labels = format!("host=\"{}\",vlan=\"{}\"", host.ip, host.vlan);
+    let _ = writeln!(out, "top_hosts_host_packets_total{{{},direction=\"sent\"}} {}", labels, host.stats.src2dst_pkts);
+    let _ = writeln!(out, "top_hosts_host_packets_total{{{},direction=\"received\"}} {}", labels, host.stats.dst2src_pkts);
+  }
+
+  let mut protocols: Vec<_> = snapshot.protocols.iter().collect();
+  protocols.sort_by(|a, b| a.0.cmp(b.0));
+
+  write_header(&mut out, "top_hosts_protocol_bytes_total", "counter", "Bytes by nDPI protocol");
+  for &(name, stats) in protocols.iter() {
+    let _ = writeln!(out, "top_hosts_protocol_bytes_total{{protocol=\"{}\"}} {}", escape_label(name), stats.bytes);
+  }
+
+  write_header(&mut out, "top_hosts_protocol_packets_total", "counter", "Packets by nDPI protocol");
+  for &(name, stats) in protocols.iter() {
+    let _ = writeln!(out, "top_hosts_protocol_packets_total{{protocol=\"{}\"}} {}", escape_label(name), stats.packets);
+  }
+
+  write_header(&mut out, "top_hosts_active_flows", "gauge", "Flows in the flows table");
+  let _ = writeln!(out, "top_hosts_active_flows {}", snapshot.flows.len());
+  write_header(&mut out, "top_hosts_active_hosts", "gauge", "Hosts in the hosts table");
+  let _ = writeln!(out, "top_hosts_active_hosts {}", snapshot.hosts.len());
+  write_header(&mut out, "top_hosts_purged_flows_total", "counter", "Flows purged after being idle");
+  let _ = writeln!(out, "top_hosts_purged_flows_total {}", snapshot.purged_flows);
+  write_header(&mut out, "top_hosts_purged_hosts_total", "counter", "Hosts purged after being idle");
+  let _ = writeln!(out, "top_hosts_purged_hosts_total {}", snapshot.purged_hosts);
+
+  write_header(&mut out, "top_hosts_bytes_total", "counter", "Bytes of all the accounted packets");
+  let _ = writeln!(out, "top_hosts_bytes_total {}", snapshot.totals.bytes());
+  write_header(&mut out, "top_hosts_packets_total", "counter", "All the accounted packets");
+  let _ = writeln!(out, "top_hosts_packets_total {}", snapshot.totals.packets());
+
+  write_header(&mut out, "top_hosts_pcap_received_total", "counter", "Packets received, as reported by pcap");
+  let _ = writeln!(out, "top_hosts_pcap_received_total {}", counters.received.load(Ordering::Relaxed));
+  write_header(&mut out, "top_hosts_pcap_dropped_total", "counter", "Packets dropped by the kernel, as reported by pcap");
+  let _ = writeln!(out, "top_hosts_pcap_dropped_total {}", counters.kernel_drops.load(Ordering::Relaxed));
+  write_header(&mut out, "top_hosts_queue_dropped_total", "counter", "Packets dropped because the processing queue was full");
+  let _ = writeln!(out, "top_hosts_queue_dropped_total {}", counters.queue_drops.load(Ordering::Relaxed));
+
+  out
+}
+
+fn handle_request(mut stream: TcpStream, shards: &Mutex<Vec<Snapshot>>, counters: &CaptureCounters,
+    top_n: usize) -> io::Result<()> {
+  let timeout = Duration::from_millis(REQUEST_TIMEOUT_MS);
+  let mut request = [0u8; 1024];
+
+  stream.set_read_timeout(Some(timeout))?;
+  stream.set_write_timeout(Some(timeout))?;
+
+  // only the request line is needed
+  let len = stream.read(&mut request)?;
+  let request = String::from_utf8_lossy(&request[..len]);
+  let mut parts = request.split_whitespace();
+  let method = parts.next().unwrap_or("");
+  let path = parts.next().unwrap_or("");
+
+  let (status, body) = if method != "GET" {
+    ("405 Method Not Allowed", String::new())
+  } else if (path != METRICS_PATH) && !path.starts_with("/metrics?") {
+    ("404 Not Found", String::new())
+  } else {
+    let snapshot = Snapshot::merge(&lock_shards(&shards));
+    ("200 OK", render(&snapshot, counters, top_n))
+  };
+
+  write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+    Connection: close\r\n\r\n{}", status, body.len(), body)?;
+  stream.flush()
+}
+
+/* binds the address and serves the requests on a new thread */
+pub fn start(address: &str, shards: Arc<Mutex<Vec<Snapshot>>>, counters: Arc<CaptureCounters>,
+    top_n: usize) -> Result<(), String> {
+  let listener = TcpListener::bind(address).map_err(|err| format!("{}: {}", address, err))?;
+
+  thread::spawn(move || {
+    for stream in listener.incoming() {
+      let result = stream.and_then(|stream| handle_request(stream, &shards, &counters, top_n));
+
+      if let Err(err) = result {
+        debug!("Metrics request failed: {}", err);
+      }
+    }
+  });
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::collections::HashMap;
+  use engine::snapshot::{HostInfo, ProtocolStats};
+  use engine::traffic_stats::TrafficStats;
+  use eui48::MacAddress;
+
+  fn host(ip: &str, vlan: u16, sent: u64, received: u64) -> HostInfo {
+    HostInfo {
+      ip: ip.parse().unwrap(),
+      vlan: vlan,
+      mac: MacAddress::nil(),
+      local: false,
+      stats: TrafficStats {
+        src2dst_pkts: 1,
+        src2dst_bytes: sent,
+        dst2src_pkts: 2,
+        dst2src_bytes: received,
+        ..Default::default()
+      },
+      num_flows: 1,
+    }
+  }
+
+  fn snapshot() -> Snapshot {
+    let mut protocols = HashMap::new();
+    protocols.insert("TLS".to_string(), ProtocolStats { packets: 3, bytes: 300 });
+    protocols.insert("a\"b\\c".to_string(), ProtocolStats { packets: 1, bytes: 10 });
+
+    Snapshot {
+      hosts: vec![host("10.0.0.1", 0, 100, 200), host("fe80::1", 10, 10, 20), host("10.0.0.2", 0, 1, 1)],
+      protocols: protocols,
+      purged_flows: 4,
+      purged_hosts: 5,
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn metric_types() {
+    let out = render(&snapshot(), &CaptureCounters::default(), 10);
+    let mut names = Vec::new();
+
+    for line in out.lines().filter(|line| line.starts_with("# TYPE ")) {
+      let parts: Vec<_> = line.split(' ').collect();
+      assert_eq!(parts.len(), 4, "{}", line);
+
+      // the _total suffix is reserved to the counters
+      let expected = if parts[2].ends_with("_total") { "counter" } else { "gauge" };
+      assert_eq!(parts[3], expected, "{}", line);
+      names.push(parts[2]);
+    }
+
+    assert_eq!(names.len(), 13);
+    assert!(names.contains(&"top_hosts_protocol_bytes_total"));
+    assert!(names.contains(&"top_hosts_active_flows"));
+
+    // each family has its help, followed by its type, before its samples
+    for name in names {
+      let help = out.find(&format!("# HELP {} ", name)).unwrap();
+      let kind = out.find(&format!("# TYPE {} ", name)).unwrap();
+      let sample = out.lines().position(|line| line.starts_with(name) && !line.starts_with('#'));
+
+      assert!(help < kind, "{}", name);
+      assert!(sample.is_some(), "{}", name);
+    }
+  }
+
+  #[test]
+  fn samples() {
+    let counters = CaptureCounters::default();
+    counters.kernel_drops.store(7, Ordering::Relaxed);
+
+    let out = render(&snapshot(), &counters, 2);
+    let lines: Vec<_> = out.lines().collect();
+
+    for expected in &[
+      "top_hosts_host_bytes_total{host=\"10.0.0.1\",vlan=\"0\",direction=\"sent\"} 100",
+      "top_hosts_host_bytes_total{host=\"10.0.0.1\",vlan=\"0\",direction=\"received\"} 200",
+      "top_hosts_host_packets_total{host=\"fe80::1\",vlan=\"10\",direction=\"received\"} 2",
+      "top_hosts_protocol_bytes_total{protocol=\"TLS\"} 300",
+      "top_hosts_protocol_packets_total{protocol=\"a\\\"b\\\\c\"} 1",
+      "top_hosts_active_hosts 3",
+      "top_hosts_purged_flows_total 4",
+      "top_hosts_pcap_dropped_total 7",
+    ] {
+      assert!(lines.contains(expected), "{}\n{}", expected, out);
+    }
+
+    // only the top 2 hosts
+    assert!(!out.contains("10.0.0.2"), "{}", out);
+  }
+}
@@ -0,0 +1,439 @@
+use std::cmp;
+use std::thread;
+use std::thread::JoinHandle;
+use std::hash::{Hash, Hasher};
+use std::collections::hash_map::DefaultHasher;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, channel, SyncSender, Sender, Receiver, TrySendError, RecvTimeoutError};
+use pcap::{Capture, Device};
+
+use engine::types::*;
+use engine::packet_handler::PacketHandler;
+use engine::datalink::decapsulate;
+use engine::snapshot::{Snapshot, Watched};
+use engine::flow::{FlowRecord, FlowEndReason};
+use engine::traffic_stats::RATE_SAMPLE_SEC;
+use capture::{Packet, PacketSource, interface_networks};
+use config::Config;
+use ipfix::IpfixExporter;
+use metrics;
+
+const PACKET_TIMEOUT_MS: i32 = 10;
+const PURGE_TIMEOUT_SEC: f64 = 3.;
+const QUEUE_SIZE: usize = 65536;
+// how often the capture thread sends the current time and reads the pcap stats
+const TICK_INTERVAL_MS: u64 = 100;
+// how often each processing thread publishes a new snapshot
+const PUBLISH_INTERVAL_MS: u64 = 500;
+
+enum CaptureEvent {
+  Packet(Packet),
+  // the current capture time, which only advances with packets when reading a file
+  Tick(Timeval),
+}
+
+#[derive(Default)]
+pub struct CaptureCounters {
+  // packets received and dropped by the kernel, as reported by pcap
+  pub received: AtomicUsize,
+  pub kernel_drops: AtomicUsize,
+  // packets dropped because the processing queue was full
+  pub queue_drops: AtomicUsize,
+}
+
+// Captures on a thread and processes the packets on N worker threads (shards),
+// each with its own flows and hosts tables. Packets are dispatched by their
+// unordered address pair, so both the directions of a flow end up on the same
+// shard. Each shard publishes snapshots of its state for the Ui.
+pub struct Pipeline {
+  shards: Arc<Mutex<Vec<Snapshot>>>,
+  pub counters: Arc<CaptureCounters>,
+  running: Arc<AtomicBool>,
+  // set when the capture ends, e.g. at the end of the file
+  finished: Arc<AtomicBool>,
+  // set when a processing thread panics, see FailureGuard
+  failed: Arc<AtomicBool>,
+  // the networks whose hosts are local
+  pub local_networks: Vec<IpNetwork>,
+  // read by the shards before publishing a snapshot
+  watched: Arc<Mutex<Watched>>,
+  filter_requests: Sender<String>,
+  filter_results: Receiver<Result<String, String>>,
+  capture_thread: JoinHandle<()>,
+  processing_threads: Vec<JoinHandle<()>>,
+}
+
+// Stops the capture when a processing thread panics, the others keep their
+// state until the pipeline is stopped
+struct FailureGuard {
+  failed: Arc<AtomicBool>,
+  running: Arc<AtomicBool>,
+}
+
+impl Drop for FailureGuard {
+  fn drop(&mut self) {
+    if thread::panicking() {
+      self.failed.store(true, Ordering::Relaxed);
+      self.running.store(false, Ordering::Relaxed);
+    }
+  }
+}
+
+/* the snapshots are replaced as a whole, so they are still consistent when a
+ * processing thread panicked while holding the lock */
+pub fn lock_shards(shards: &Mutex<Vec<Snapshot>>) -> MutexGuard<Vec<Snapshot>> {
+  shards.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn open_source(config: &Config) -> Result<PacketSource, String> {
+  let mut source = if let Some(ref path) = config.read_file {
+    let cap = Capture::from_file(path).map_err(|err| format!("{}: {}", path, err))?;
+    PacketSource::offline(cap, config.speed)
+  } else {
+    let device: Device = match config.interface {
+      Some(ref name) => name.as_str().into(),
+      None => Device::lookup().map_err(|err| err.to_string())?,
+    };
+
+    let cap = Capture::from_device(device).and_then(|cap| {
+      cap.promisc(config.promisc)
+        .snaplen(config.snaplen)
+        .timeout(PACKET_TIMEOUT_MS)
+        .immediate_mode(true)
+        .open()
+    }).map_err(|err| err.to_string())?;
+
+    PacketSource::live(cap)
+  };
+
+  if let Some(ref filter) = config.filter {
+    source.set_filter(filter)?;
+  }
+
+  Ok(source)
+}
+
+/* the configured local networks and, when capturing, the ones of the interface */
+fn local_networks(config: &Config) -> Vec<IpNetwork> {
+  let mut networks = config.local_networks.clone();
+
+  if config.read_file.is_none() {
+    let name = match config.interface {
+      Some(ref name) => Some(name.clone()),
+      None => Device::lookup().ok().map(|device| device.name),
+    };
+
+    if let Some(name) = name {
+      networks.extend(interface_networks(&name));
+    }
+  }
+
+  networks
+}
+
+/* the IP addresses bytes, without parsing the transport header */
+fn ip_addresses(ip: &[u8]) -> Option<(&[u8], &[u8])> {
+  match ip.first().map(|byte| byte >> 4) {
+    Some(4) if ip.len() >= 20 => Some((&ip[12..16], &ip[16..20])),
+    Some(6) if ip.len() >= 40 => Some((&ip[8..24], &ip[24..40])),
+    _ => None,
+  }
+}
+
+/* by the unordered address pair, so that both the directions of a flow, its
+ * fragments and the flows within a tunnel go to the same shard. Packets which
+ * cannot be parsed go to the first shard, which will discard them */
+fn packet_shard(packet: &Packet, num_shards: usize) -> usize {
+  if num_shards == 1 {
+    return 0;
+  }
+
+  match decapsulate(packet.linktype, &packet.data).and_then(|link| ip_addresses(link.ip)) {
+    Some((saddr, daddr)) => {
+      let mut hasher = DefaultHasher::new();
+      cmp::min(saddr, daddr).hash(&mut hasher);
+      cmp::max(saddr, daddr).hash(&mut hasher);
+      (hasher.finish() % (num_shards as u64)) as usize
+    },
+    _ => 0,
+  }
+}
+
+fn capture_loop(mut source: PacketSource, queues: Vec<SyncSender<CaptureEvent>>, counters: Arc<CaptureCounters>,
+    running: Arc<AtomicBool>, filter_requests: Receiver<String>, filter_results: Sender<Result<String, String>>) {
+  let mut last_tick = SystemTime::now();
+  let tick_interval = Duration::from_millis(TICK_INTERVAL_MS);
+
+  while running.load(Ordering::Relaxed) && !source.is_eof() {
+    while let Ok(filter) = filter_requests.try_recv() {
+      let _ = filter_results.send(source.set_filter(&filter).map(|_| filter));
+    }
+
+    if let Some(packet) = source.next() {
+      let queue = &queues[packet_shard(&packet, queues.len())];
+
+      if source.is_offline() {
+        // never drop packets read from a file
+        if queue.send(CaptureEvent::Packet(packet)).is_err() {
+          break;
+        }
+      } else {
+        match queue.try_send(CaptureEvent::Packet(packet)) {
+          Err(TrySendError::Full(_)) => { counters.queue_drops.fetch_add(1, Ordering::Relaxed); },
+          Err(TrySendError::Disconnected(_)) => break,
+          Ok(_) => (),
+        }
+      }
+    }
+
+    if last_tick.elapsed().unwrap_or_default() >= tick_interval {
+      for queue in queues.iter() {
+        let _ = queue.try_send(CaptureEvent::Tick(source.now()));
+      }
+
+      if let Some((received, dropped)) = source.stats() {
+        counters.received.store(received, Ordering::Relaxed);
+        counters.kernel_drops.store(dropped, Ordering::Relaxed);
+      }
+
+      last_tick = SystemTime::now();
+    }
+  }
+
+  for queue in queues.iter() {
+    let _ = queue.send(CaptureEvent::Tick(source.now()));
+  }
+}
+
+fn export_records(exporter: &mut Option<IpfixExporter>, records: &[FlowRecord], now: &Timeval) {
+  if let Some(ref mut exporter) = *exporter {
+    if let Err(err) = exporter.export(records, now) {
+      warn!("IPFIX export failed: {}", err);
+    }
+  }
+}
+
+fn processing_loop(config: Config, queue: Receiver<CaptureEvent>, shards: Arc<Mutex<Vec<Snapshot>>>, shard: usize,
+    mut exporter: Option<IpfixExporter>, local_networks: Vec<IpNetwork>, watched: Arc<Mutex<Watched>>) {
+  let mut handler = PacketHandler::new(config.flow_idle_timeout, config.host_idle_timeout, config.decap, local_networks);
+  let stats_timeout = config.stats_timeout as i64;
+  let publish_interval = Duration::from_millis(PUBLISH_INTERVAL_MS);
+  let mut now: Timeval = Default::default();
+  let mut last_update: Timeval = Default::default();
+  let mut last_sample: Timeval = Default::default();
+  let mut last_purge: Timeval = Default::default();
+  let mut last_publish = SystemTime::now();
+
+  loop {
+    match queue.recv_timeout(Duration::from_millis(PACKET_TIMEOUT_MS as u64)) {
+      Ok(CaptureEvent::Packet(packet)) => {
+        let ts: Timeval = packet.header.ts.into();
+
+        if ts - now > 0. {
+          now = ts;
+        }
+
+        handler.process_packet(packet.linktype, &packet.header, &packet.data);
+      },
+      Ok(CaptureEvent::Tick(tick)) => {
+        if tick - now > 0. {
+          now = tick;
+        }
+      },
+      Err(RecvTimeoutError::Timeout) => (),
+      Err(RecvTimeoutError::Disconnected) => break,
+    }
+
+    if now == Default::default() {
+      continue;
+    } else if last_update == Default::default() {
+      last_update = now;
+      last_sample = now;
+      last_purge = now;
+    }
+
+    // the smoothed rates need finer samples than the stats interval
+    if now - last_sample >= RATE_SAMPLE_SEC {
+      handler.sample_rates(&now);
+      last_sample = now;
+    }
+
+    // on multiples of the interval, so that the shards histories line up, see TrafficStats::merge
+    let update_time = Timeval { sec: now.sec - now.sec % stats_timeout, usec: 0 };
+
+    if update_time - last_update > 0. {
+      handler.update_stats(&update_time);
+      last_update = update_time;
+    }
+
+    if now - last_purge >= PURGE_TIMEOUT_SEC {
+      let expired = handler.purge_idle(&now);
+
+      if exporter.is_some() {
+        let active = handler.export_active(&now, config.ipfix_active_timeout, FlowEndReason::ActiveTimeout);
+        export_records(&mut exporter, &expired, &now);
+        export_records(&mut exporter, &active, &now);
+      }
+
+      last_purge = now;
+    }
+
+    if last_publish.elapsed().unwrap_or_default() >= publish_interval {
+      handler.set_watched(*watched.lock().unwrap_or_else(PoisonError::into_inner));
+      let snapshot = handler.snapshot(&now);
+      lock_shards(&shards)[shard] = snapshot;
+      last_publish = SystemTime::now();
+    }
+  }
+
+  // end of the capture file or stop requested
+  if exporter.is_some() {
+    let remaining = handler.export_active(&now, 0, FlowEndReason::ForcedEnd);
+    export_records(&mut exporter, &remaining, &now);
+  }
+
+  handler.sample_rates(&now);
+  handler.update_stats(&now);
+  let snapshot = handler.snapshot(&now);
+  lock_shards(&shards)[shard] = snapshot;
+}
+
+impl Pipeline {
+  pub fn start(config: &Config) -> Result<Pipeline, String> {
+    let num_shards = config.threads;
+    let shards = Arc::new(Mutex::new(vec![Snapshot::default(); num_shards]));
+    let counters = Arc::new(CaptureCounters::default());
+    let running = Arc::new(AtomicBool::new(true));
+    let failed = Arc::new(AtomicBool::new(false));
+    let watched = Arc::new(Mutex::new(Watched::default()));
+    let (requests_tx, requests_rx) = channel();
+    let (results_tx, results_rx) = channel();
+    let (init_tx, init_rx) = channel();
+    let mut queues = Vec::new();
+    let mut processing_threads = Vec::new();
+    let local_networks = local_networks(config);
+
+    for network in local_networks.iter() {
+      info!("Local network: {}", network);
+    }
+
+    for shard in 0..num_shards {
+      let (queue_tx, queue_rx) = sync_channel(cmp::max(QUEUE_SIZE / num_shards, 1));
+      let processing_config = config.clone();
+      let processing_shards = shards.clone();
+      let processing_networks = local_networks.clone();
+      let processing_watched = watched.clone();
+
+      // one observation domain per shard
+      let exporter = match config.ipfix_collector {
+        Some(ref collector) => Some(IpfixExporter::new(collector, shard as u32)
+          .map_err(|err| format!("{}: {}", collector, err))?),
+        None => None,
+      };
+
+      let guard = FailureGuard { failed: failed.clone(), running: running.clone() };
+
+      queues.push(queue_tx);
+      processing_threads.push(thread::spawn(move || {
+        let _guard = guard;
+        processing_loop(processing_config, queue_rx, processing_shards, shard, exporter, processing_networks,
+          processing_watched);
+      }));
+    }
+
+    // the capture is opened by its own thread, which reports the outcome
+    let capture_config = config.clone();
+    let capture_counters = counters.clone();
+    let capture_running = running.clone();
+    let finished = Arc::new(AtomicBool::new(false));
+    let capture_finished = finished.clone();
+    let capture_thread = thread::spawn(move || {
+      match open_source(&capture_config) {
+        Ok(source) => {
+          let _ = init_tx.send(Ok(()));
+          capture_loop(source, queues, capture_counters, capture_running, requests_rx, results_tx);
+        },
+        Err(err) => { let _ = init_tx.send(Err(err)); },
+      }
+
+      capture_finished.store(true, Ordering::Relaxed);
+    });
+
+    init_rx.recv().unwrap_or(Err("Capture thread failure".to_string()))?;
+
+    if let Some(ref address) = config.metrics_address {
+      metrics::start(address, shards.clone(), counters.clone(), config.top_n)?;
+    }
+
+    Ok(Pipeline {
+      shards: shards,
+      counters: counters,
+      local_networks: local_networks,
+      watched: watched,
+      running: running,
+      finished: finished,
+      failed: failed,
+      filter_requests: requests_tx,
+      filter_results: results_rx,
+      capture_thread: capture_thread,
+      processing_threads: processing_threads,
+    })
+  }
+
+  /* the outcome is returned by poll_filter_result */
+  pub fn set_filter(&self, filter: String) {
+    let _ = self.filter_requests.send(filter);
+  }
+
+  /* the applied filter or an error message */
+  pub fn poll_filter_result(&self) -> Option<Result<String, String>> {
+    self.filter_results.try_recv().ok()
+  }
+
+  /* true when there are no more packets to capture, wait returns the final snapshot */
+  pub fn is_finished(&self) -> bool {
+    self.finished.load(Ordering::Relaxed)
+  }
+
+  /* true when a processing thread panicked, which also stops the capture.
+   * wait and stop then return an error */
+  pub fn has_failed(&self) -> bool {
+    self.failed.load(Ordering::Relaxed)
+  }
+
+  /* the history of these items is part of the next snapshots */
+  pub fn watch(&self, watched: Watched) {
+    *self.watched.lock().unwrap_or_else(PoisonError::into_inner) = watched;
+  }
+
+  /* the latest snapshots published by the shards, merged */
+  pub fn snapshot(&self) -> Snapshot {
+    Snapshot::merge(&lock_shards(&self.shards))
+  }
+
+  /* waits for all the packets to be processed, then returns the final snapshot */
+  pub fn wait(self) -> Result<Snapshot, String> {
+    if self.capture_thread.join().is_err() {
+      return Err("Capture thread failure".to_string());
+    }
+
+    let mut failed = false;
+
+    for thread in self.processing_threads {
+      failed |= thread.join().is_err();
+    }
+
+    if failed {
+      return Err("Packet processing thread failure".to_string());
+    }
+
+    let snapshot = Snapshot::merge(&lock_shards(&self.shards));
+    Ok(snapshot)
+  }
+
+  pub fn stop(self) -> Result<Snapshot, String> {
+    self.running.store(false, Ordering::Relaxed);
+    self.wait()
+  }
+}
@@ -0,0 +1,93 @@
+use std::io::{Write, Result};
+
+use engine::snapshot::{Snapshot, top_groups};
+use engine::aggregate::{Aggregator, AggregationMode};
+use engine::types::FlowSort;
+use ui::{format_host, format_bytes, format_bits, format_flow_endpoint, format_time};
+
+fn write_hosts<W: Write>(out: &mut W, snapshot: &Snapshot, top_n: usize) -> Result<()> {
+  writeln!(out, "{:<40} {:<17} {:>6} {:>10} {:>12} {:>12} {:>12} {:>12} {:>12}",
+    "Host", "MAC", "Flows", "Packets", "Sent", "Received", "TX", "RX", "Thpt")?;
+
+  for host in snapshot.top_hosts(FlowSort::Bytes, false).iter().take(top_n) {
+    writeln!(out, "{:<40} {:<17} {:>6} {:>10} {:>12} {:>12} {:>12} {:>12} {:>12}",
+      format_host(host.ip, host.vlan),
+      host.mac.to_hex_string(),
+      host.num_flows,
+      host.stats.packets(),
+      format_bytes(host.stats.src2dst_bytes as f64),
+      format_bytes(host.stats.dst2src_bytes as f64),
+      format_bits(host.stats.src2dst_throughput * 8 as f64),
+      format_bits(host.stats.dst2src_throughput * 8 as f64),
+      format_bits(host.stats.throughput * 8 as f64))?;
+  }
+
+  Ok(())
+}
+
+fn write_groups<W: Write>(out: &mut W, snapshot: &Snapshot, top_n: usize, aggregator: &Aggregator) -> Result<()> {
+  let groups = aggregator.aggregate(snapshot.hosts.iter(), &snapshot.flows);
+
+  writeln!(out, "{:<40} {:>6} {:>6} {:>10} {:>12} {:>12} {:>12} {:>12} {:>12}",
+    "Group", "Hosts", "Flows", "Packets", "Sent", "Received", "TX", "RX", "Thpt")?;
+
+  for group in top_groups(&groups, FlowSort::Bytes, false).iter().take(top_n) {
+    writeln!(out, "{:<40} {:>6} {:>6} {:>10} {:>12} {:>12} {:>12} {:>12} {:>12}",
+      group.name,
+      group.num_hosts,
+      group.num_flows,
+      group.stats.packets(),
+      format_bytes(group.stats.src2dst_bytes as f64),
+      format_bytes(group.stats.dst2src_bytes as f64),
+      format_bits(group.stats.src2dst_throughput * 8 as f64),
+      format_bits(group.stats.dst2src_throughput * 8 as f64),
+      format_bits(group.stats.throughput * 8 as f64))?;
+  }
+
+  Ok(())
+}
+
+// Summary line preceding each report in batch mode
+pub fn write_header<W: Write>(out: &mut W, snapshot: &Snapshot, kernel_drops: usize, queue_drops: usize) -> Result<()> {
+  writeln!(out, "top_hosts - {} | {} flows, {} hosts | Total: {} ({} packets) | Drops: {} kernel, {} queue",
+    format_time(&snapshot.when),
+    snapshot.flows.len(),
+    snapshot.hosts.len(),
+    format_bits(snapshot.totals.throughput * 8 as f64),
+    snapshot.totals.packets(),
+    kernel_drops,
+    queue_drops)?;
+  writeln!(out)
+}
+
+// Plain text report of the top hosts (or host groups) and flows
+pub fn write_report<W: Write>(out: &mut W, snapshot: &Snapshot, top_n: usize, aggregator: &Aggregator) -> Result<()> {
+  if aggregator.mode == AggregationMode::Host {
+    write_hosts(out, snapshot, top_n)?;
+  } else {
+    write_groups(out, snapshot, top_n, aggregator)?;
+  }
+
+  // like the Ui, upload and download when there are local hosts
+  let (tx, rx) = if snapshot.hosts.iter().any(|host| host.local) { ("Up", "Down") } else { ("TX", "RX") };
+
+  writeln!(out)?;
+  writeln!(out, "{:<47} {:<47} {:<16} {:>10} {:>12} {:>12} {:>12} {:>12}",
+    "Source", "Destination", "Proto", "Packets", "Traffic", tx, rx, "Thpt")?;
+
+  for flow in snapshot.top_flows(FlowSort::Bytes, false).iter().take(top_n) {
+    let (up, down) = flow.up_down_throughput();
+
+    writeln!(out, "{:<47} {:<47} {:<16} {:>10} {:>12} {:>12} {:>12} {:>12}",
+      format_flow_endpoint(flow.proto, flow.saddr, flow.sport),
+      format_flow_endpoint(flow.proto, flow.daddr, flow.dport),
+      flow.protocol,
+      flow.stats.packets(),
+      format_bytes(flow.stats.bytes() as f64),
+      format_bits(up * 8 as f64),
+      format_bits(down * 8 as f64),
+      format_bits(flow.stats.throughput * 8 as f64))?;
+  }
+
+  Ok(())
+}
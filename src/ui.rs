@@ -1,11 +1,16 @@
 use std::io;
 use std::cmp;
-use engine::flow::Flow;
-use engine::packet_handler::PacketHandler;
+use engine::snapshot::{Snapshot, FlowDirection, Watched, top_groups};
+use engine::aggregate::{Aggregator, AggregationMode};
+use engine::filter::FlowFilter;
+use std::collections::HashSet;
+use engine::types::{FlowSort, Timeval, PacketTuple, HostKey, has_ports, is_icmp};
 use tui::Terminal;
 use tui::terminal::Terminal as TerminalType;
 use tui::backend::RawBackend;
 use std::slice::Iter;
+use std::net::{IpAddr, SocketAddr};
+use termion::event::Key;
 
 use tui::widgets::*;
 use tui::layout::*;
@@ -36,51 +41,730 @@ pub fn format_bits(num: f64) -> String {
   format_value(num, &units, 1000_f64)
 }
 
+// IPv6 addresses are bracketed, e.g. [::1]:53
+pub fn format_endpoint(ip: IpAddr, port: u16) -> String {
+  SocketAddr::new(ip, port).to_string()
+}
+
+/* the VLAN ID is omitted when untagged */
+pub fn format_host(ip: IpAddr, vlan: u16) -> String {
+  if vlan != 0 {
+    format!("{} vlan {}", ip, vlan)
+  } else {
+    ip.to_string()
+  }
+}
+
+/* the port is omitted for the protocols without ports */
+pub fn format_flow_endpoint(proto: u8, ip: IpAddr, port: u16) -> String {
+  if has_ports(proto) {
+    format_endpoint(ip, port)
+  } else {
+    ip.to_string()
+  }
+}
+
+pub fn format_duration(secs: i64) -> String {
+  if secs < 60 {
+    format!("{}s", secs)
+  } else if secs < 3600 {
+    format!("{}m{}s", secs / 60, secs % 60)
+  } else {
+    format!("{}h{}m", secs / 3600, (secs % 3600) / 60)
+  }
+}
+
+/* local time as HH:MM:SS */
+pub fn format_time(tv: &Timeval) -> String {
+  let secs = tv.sec as ::libc::time_t;
+  let mut tm: ::libc::tm = unsafe { ::std::mem::zeroed() };
+
+  unsafe { ::libc::localtime_r(&secs, &mut tm) };
+  format!("{:02}:{:02}:{:02}", tm.tm_hour, tm.tm_min, tm.tm_sec)
+}
+
+// Actions requested by the user which the Ui cannot handle by itself
+pub enum UiEvent {
+  Quit,
+  SetCaptureFilter(String),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum View {
+  Flows,
+  Hosts,
+}
+
+// The item shown in the detail pane
+#[derive(Clone, Copy, PartialEq)]
+enum Detail {
+  Flow(PacketTuple),
+  Host(HostKey),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum PromptKind {
+  CaptureFilter,
+  Search,
+}
+
+// A single line text input shown in the status bar
+struct Prompt {
+  kind: PromptKind,
+  label: &'static str,
+  input: String,
+}
+
+const DETAIL_HEIGHT: u16 = 14;
+const CHART_HEIGHT: u16 = 5;
+
+struct Column {
+  title: &'static str,
+  // percentage of the screen width
+  width: u16,
+  sort: Option<FlowSort>,
+}
+
+// TX: source to destination, RX: destination to source
+const FLOW_COLUMNS: &[Column] = &[
+  Column { title: "Source", width: 20, sort: Some(FlowSort::Source) },
+  Column { title: "Destination", width: 20, sort: Some(FlowSort::Destination) },
+  Column { title: "Proto", width: 12, sort: Some(FlowSort::Protocol) },
+  Column { title: "Packets", width: 7, sort: Some(FlowSort::Packets) },
+  Column { title: "Traffic", width: 9, sort: Some(FlowSort::Bytes) },
+  Column { title: "Age", width: 7, sort: Some(FlowSort::Age) },
+  Column { title: "TX", width: 8, sort: Some(FlowSort::TxThroughput) },
+  Column { title: "RX", width: 8, sort: Some(FlowSort::RxThroughput) },
+  Column { title: "Thpt", width: 9, sort: Some(FlowSort::Throughput) },
+];
+
+// TX: sent by the host, RX: received by the host
+const HOST_COLUMNS: &[Column] = &[
+  Column { title: "Host", width: 24, sort: Some(FlowSort::Source) },
+  Column { title: "MAC", width: 16, sort: None },
+  Column { title: "Flows", width: 6, sort: None },
+  Column { title: "Packets", width: 8, sort: Some(FlowSort::Packets) },
+  Column { title: "Sent", width: 9, sort: None },
+  Column { title: "Received", width: 9, sort: None },
+  Column { title: "TX", width: 9, sort: Some(FlowSort::TxThroughput) },
+  Column { title: "RX", width: 9, sort: Some(FlowSort::RxThroughput) },
+  Column { title: "Thpt", width: 9, sort: Some(FlowSort::Throughput) },
+];
+
+// FLOW_COLUMNS when the local networks are known
+const LOCAL_FLOW_COLUMNS: &[Column] = &[
+  Column { title: "Source", width: 20, sort: Some(FlowSort::Source) },
+  Column { title: "Destination", width: 20, sort: Some(FlowSort::Destination) },
+  Column { title: "Proto", width: 12, sort: Some(FlowSort::Protocol) },
+  Column { title: "Packets", width: 7, sort: Some(FlowSort::Packets) },
+  Column { title: "Traffic", width: 9, sort: Some(FlowSort::Bytes) },
+  Column { title: "Age", width: 7, sort: Some(FlowSort::Age) },
+  Column { title: "Up", width: 8, sort: Some(FlowSort::TxThroughput) },
+  Column { title: "Down", width: 8, sort: Some(FlowSort::RxThroughput) },
+  Column { title: "Thpt", width: 9, sort: Some(FlowSort::Throughput) },
+];
+
+// HOST_COLUMNS when the hosts are aggregated
+const GROUP_COLUMNS: &[Column] = &[
+  Column { title: "Group", width: 24, sort: Some(FlowSort::Source) },
+  Column { title: "Hosts", width: 6, sort: None },
+  Column { title: "Flows", width: 6, sort: None },
+  Column { title: "Packets", width: 8, sort: Some(FlowSort::Packets) },
+  Column { title: "Sent", width: 9, sort: None },
+  Column { title: "Received", width: 9, sort: None },
+  Column { title: "TX", width: 9, sort: Some(FlowSort::TxThroughput) },
+  Column { title: "RX", width: 9, sort: Some(FlowSort::RxThroughput) },
+  Column { title: "Thpt", width: 9, sort: Some(FlowSort::Throughput) },
+];
+
+/* the sort keys without a column need to be shown somewhere */
+fn sort_label(sort: FlowSort) -> &'static str {
+  match sort {
+    FlowSort::Throughput => "thpt",
+    FlowSort::TxThroughput => "tx",
+    FlowSort::RxThroughput => "rx",
+    FlowSort::RateShort => "1s avg",
+    FlowSort::RateMedium => "10s avg",
+    FlowSort::RateLong => "60s avg",
+    FlowSort::PeakThroughput => "peak",
+    FlowSort::Bytes => "traffic",
+    FlowSort::Packets => "packets",
+    FlowSort::Protocol => "proto",
+    FlowSort::Age => "age",
+    FlowSort::Source => "source",
+    FlowSort::Destination => "destination",
+  }
+}
+
 pub struct Ui {
   term: TerminalType<RawBackend>,
+  view: View,
+  sort: FlowSort,
+  reverse: bool,
+  // indexed by the column position in FLOW_COLUMNS/HOST_COLUMNS/GROUP_COLUMNS
+  hidden_flow_columns: Vec<bool>,
+  hidden_host_columns: Vec<bool>,
+  hidden_group_columns: Vec<bool>,
+  prompt: Option<Prompt>,
+  capture_filter: String,
+  search: String,
+  display_filter: FlowFilter,
+  message: Option<String>,
+  selected: usize,
+  offset: usize,
+  // from the last draw, to bound the navigation
+  num_rows: usize,
+  page_size: usize,
+  row_keys: Vec<Detail>,
+  // the flow or host shown in the detail pane
+  detail: Option<Detail>,
+  snapshot: Snapshot,
+  // how the hosts view groups the hosts
+  aggregator: Aggregator,
+  // show upload and download instead of TX and RX
+  has_local_networks: bool,
+  // only show the local hosts in the hosts view
+  local_only: bool,
+  // when paused the snapshot is not updated
+  paused: bool,
+  kernel_drops: usize,
+  queue_drops: usize,
 }
 
 impl Ui {
-  pub fn new() -> Ui {
+  pub fn new(aggregator: Aggregator, has_local_networks: bool) -> Ui {
     let backend = RawBackend::new().unwrap();
 
     Ui {
-      term: Terminal::new(backend).unwrap()
+      term: Terminal::new(backend).unwrap(),
+      view: View::Flows,
+      sort: FlowSort::Throughput,
+      reverse: false,
+      hidden_flow_columns: vec![false; FLOW_COLUMNS.len()],
+      hidden_host_columns: vec![false; HOST_COLUMNS.len()],
+      hidden_group_columns: vec![false; GROUP_COLUMNS.len()],
+      prompt: None,
+      capture_filter: String::new(),
+      search: String::new(),
+      display_filter: Default::default(),
+      message: None,
+      selected: 0,
+      offset: 0,
+      num_rows: 0,
+      page_size: 0,
+      row_keys: Vec::new(),
+      detail: None,
+      snapshot: Default::default(),
+      aggregator: aggregator,
+      has_local_networks: has_local_networks,
+      local_only: false,
+      paused: false,
+      kernel_drops: 0,
+      queue_drops: 0,
     }
   }
 
-  pub fn draw(&mut self, flows: &Vec<&Flow>, handler: &PacketHandler) -> Result<(), io::Error> {
+  /* packets dropped by the kernel and by the processing queue */
+  pub fn set_drops(&mut self, kernel_drops: usize, queue_drops: usize) {
+    self.kernel_drops = kernel_drops;
+    self.queue_drops = queue_drops;
+  }
+
+  pub fn is_paused(&self) -> bool {
+    self.paused
+  }
+
+  /* the items whose history is drawn, see Pipeline::watch */
+  pub fn watched(&self) -> Watched {
+    match self.detail {
+      Some(Detail::Flow(key)) => Watched { flow: Some(key), host: None },
+      Some(Detail::Host(key)) => Watched { flow: None, host: Some(key) },
+      None => Default::default(),
+    }
+  }
+
+  /* the data to draw, ignored while paused */
+  pub fn update(&mut self, snapshot: Snapshot) {
+    if !self.paused {
+      self.snapshot = snapshot;
+    }
+  }
+
+  pub fn set_capture_filter(&mut self, filter: &str) {
+    self.capture_filter = filter.to_string();
+  }
+
+  /* shown in the status bar until the next key press */
+  pub fn show_message(&mut self, msg: String) {
+    self.message = Some(msg);
+  }
+
+  fn move_selection(&mut self, delta: isize) {
+    let last = self.num_rows.saturating_sub(1) as isize;
+    self.selected = cmp::max(cmp::min(self.selected as isize + delta, last), 0) as usize;
+  }
+
+  fn handle_prompt_key(&mut self, key: Key) -> Option<UiEvent> {
+    let mut prompt = self.prompt.take().unwrap();
+
+    match key {
+      Key::Char('\n') => {
+        match prompt.kind {
+          PromptKind::CaptureFilter => return Some(UiEvent::SetCaptureFilter(prompt.input)),
+          PromptKind::Search => {
+            match FlowFilter::parse(&prompt.input) {
+              Ok(filter) => {
+                self.display_filter = filter;
+                self.search = prompt.input;
+                self.selected = 0;
+              },
+              Err(err) => self.message = Some(err),
+            }
+            return None;
+          },
+        }
+      },
+      Key::Esc => return None,
+      Key::Backspace => { prompt.input.pop(); },
+      Key::Char(c) => prompt.input.push(c),
+      _ => (),
+    }
+
+    self.prompt = Some(prompt);
+    None
+  }
+
+  pub fn handle_key(&mut self, key: Key) -> Option<UiEvent> {
+    self.message = None;
+
+    if self.prompt.is_some() {
+      return self.handle_prompt_key(key);
+    }
+
+    match key {
+      Key::Char('q') => Some(UiEvent::Quit),
+      Key::Char('\t') => {
+        self.view = match self.view {
+          View::Flows => View::Hosts,
+          View::Hosts => View::Flows,
+        };
+        self.selected = 0;
+        self.offset = 0;
+        self.detail = None;
+        None
+      },
+      Key::Up => { self.move_selection(-1); None },
+      Key::Down => { self.move_selection(1); None },
+      Key::PageUp => { let page = self.page_size as isize; self.move_selection(-page); None },
+      Key::PageDown => { let page = self.page_size as isize; self.move_selection(page); None },
+      Key::Home => { self.selected = 0; None },
+      Key::End => { self.selected = self.num_rows.saturating_sub(1); None },
+      Key::Char('\n') => {
+        self.detail = match self.detail {
+          Some(_) => None,
+          None => self.row_keys.get(self.selected).cloned(),
+        };
+        None
+      },
+      Key::Esc => { self.detail = None; None },
+      Key::Char('s') => {
+        self.sort = self.sort.next();
+        None
+      },
+      Key::Char('r') => {
+        self.reverse = !self.reverse;
+        None
+      },
+      Key::Char('a') => {
+        self.aggregator.next_mode();
+        self.selected = 0;
+        None
+      },
+      Key::Char('l') => {
+        self.local_only = !self.local_only;
+        self.selected = 0;
+        None
+      },
+      Key::Char(c @ '1'..='9') => {
+        let hidden = match self.view {
+          View::Flows => &mut self.hidden_flow_columns,
+          View::Hosts if self.aggregator.mode == AggregationMode::Host => &mut self.hidden_host_columns,
+          View::Hosts => &mut self.hidden_group_columns,
+        };
+        let idx = (c as usize) - ('1' as usize);
+
+        if idx < hidden.len() {
+          hidden[idx] = !hidden[idx];
+        }
+        None
+      },
+      Key::Char('p') => {
+        self.paused = !self.paused;
+        None
+      },
+      Key::Char(' ') => {
+        self.paused = false;
+        None
+      },
+      Key::Char('/') => {
+        self.prompt = Some(Prompt {
+          kind: PromptKind::Search,
+          label: "Search: ",
+          input: self.search.clone(),
+        });
+        None
+      },
+      Key::Char('f') => {
+        self.prompt = Some(Prompt {
+          kind: PromptKind::CaptureFilter,
+          label: "BPF filter: ",
+          input: self.capture_filter.clone(),
+        });
+        None
+      },
+      _ => None,
+    }
+  }
+
+  fn status_text(&self) -> String {
+    if let Some(ref prompt) = self.prompt {
+      return format!("{}{}_", prompt.label, prompt.input);
+    }
+
+    if let Some(ref msg) = self.message {
+      return msg.clone();
+    }
+
+    let filter = if self.capture_filter.is_empty() { "none" } else { &self.capture_filter };
+    let search = if self.search.is_empty() { String::new() } else { format!(" Search: {} |", self.search) };
+
+    let drops = if (self.kernel_drops + self.queue_drops) == 0 { String::new() } else {
+      format!("Drops: {} kernel, {} queue | ", self.kernel_drops, self.queue_drops)
+    };
+
+    if self.paused {
+      return format!("PAUSED at {} | {}Filter: {} |{} p/space: resume, q: quit",
+        format_time(&self.snapshot.when), drops, filter, search);
+    }
+
+    let hosts = if self.local_only { "local" } else { "all" };
+
+    format!("{}Filter: {} |{} Sort: {} | Group: {} | Hosts: {} | tab: flows/hosts, enter: details, /: search, \
+      s: sort, r: reverse, a: group hosts, l: local hosts, 1-9: toggle column, p: pause, f: capture filter, q: quit",
+      drops, filter, search, sort_label(self.sort), self.aggregator.mode_name(), hosts)
+  }
+
+  fn flows_rows(&mut self) -> Vec<Vec<String>> {
+    let now = self.snapshot.when;
+    let flows: Vec<_> = self.snapshot.top_flows(self.sort, self.reverse).into_iter()
+      .filter(|flow| self.display_filter.matches_flow(flow))
+      .collect();
+
+    self.row_keys = flows.iter().map(|flow| Detail::Flow(flow.key)).collect();
+
+    flows.iter().map(|flow| {
+      let (up, down) = flow.up_down_throughput();
+
+      vec![
+        format_flow_endpoint(flow.proto, flow.saddr, flow.sport),
+        format_flow_endpoint(flow.proto, flow.daddr, flow.dport),
+        flow.protocol.to_string(),
+        flow.stats.packets().to_string(),
+        format_bytes(flow.stats.bytes() as f64),
+        format_duration(now.sec - flow.stats.first_seen.sec),
+        format_bits(up * 8 as f64),
+        format_bits(down * 8 as f64),
+        format_bits(flow.stats.throughput * 8 as f64)]
+    }).collect()
+  }
+
+  fn hosts_rows(&mut self) -> Vec<Vec<String>> {
+    let mut matching_flows_hosts = HashSet::new();
+
+    if !self.display_filter.is_empty() {
+      for flow in self.snapshot.flows.iter() {
+        if self.display_filter.matches_flow(flow) {
+          matching_flows_hosts.insert(flow.key.src_host());
+          matching_flows_hosts.insert(flow.key.dst_host());
+        }
+      }
+    }
+
+    let hosts: Vec<_> = self.snapshot.top_hosts(self.sort, self.reverse).into_iter().filter(|host| {
+      (host.local || !self.local_only) && self.display_filter.matches_host(host, &matching_flows_hosts)
+    }).collect();
+
+    if self.aggregator.mode != AggregationMode::Host {
+      // the groups have no detail
+      self.row_keys.clear();
+      let groups = self.aggregator.aggregate(hosts.into_iter(), &self.snapshot.flows);
+
+      return top_groups(&groups, self.sort, self.reverse).iter().map(|group| {
+        vec![
+          group.name.clone(),
+          group.num_hosts.to_string(),
+          group.num_flows.to_string(),
+          group.stats.packets().to_string(),
+          format_bytes(group.stats.src2dst_bytes as f64),
+          format_bytes(group.stats.dst2src_bytes as f64),
+          format_bits(group.stats.src2dst_throughput * 8 as f64),
+          format_bits(group.stats.dst2src_throughput * 8 as f64),
+          format_bits(group.stats.throughput * 8 as f64)]
+      }).collect();
+    }
+
+    self.row_keys = hosts.iter().map(|host| Detail::Host(host.key())).collect();
+
+    hosts.iter().map(|host| {
+      vec![
+        format_host(host.ip, host.vlan),
+        host.mac.to_hex_string(),
+        host.num_flows.to_string(),
+        host.stats.packets().to_string(),
+        format_bytes(host.stats.src2dst_bytes as f64),
+        format_bytes(host.stats.dst2src_bytes as f64),
+        format_bits(host.stats.src2dst_throughput * 8 as f64),
+        format_bits(host.stats.dst2src_throughput * 8 as f64),
+        format_bits(host.stats.throughput * 8 as f64)]
+    }).collect()
+  }
+
+  /* the detail title, text and throughput history */
+  fn detail_pane(&self, detail: &Detail) -> (&'static str, String, Vec<u64>) {
+    match *detail {
+      Detail::Flow(ref key) => {
+        let (text, history) = self.flow_detail(key);
+        ("Flow details", text, history)
+      },
+      Detail::Host(ref key) => {
+        let (text, history) = self.host_detail(key);
+        ("Host details", text, history)
+      },
+    }
+  }
+
+  fn host_detail(&self, key: &HostKey) -> (String, Vec<u64>) {
+    let now = self.snapshot.when;
+    let host = match self.snapshot.hosts.iter().find(|host| host.key() == *key) {
+      Some(host) => host,
+      None => return ("The host has expired".to_string(), Vec::new()),
+    };
+    let stats = &host.stats;
+
+    let mut text = format!("{} [{}]\n", format_host(host.ip, host.vlan), host.mac.to_hex_string());
+    if self.has_local_networks {
+      text += &format!("Network:      {}\n", if host.local { "local" } else { "remote" });
+    }
+    text += &format!("Flows:        {}\n", host.num_flows);
+    text += &format!("Sent:         {} packets, {}, {}\n", stats.src2dst_pkts,
+      format_bytes(stats.src2dst_bytes as f64), format_bits(stats.src2dst_throughput * 8 as f64));
+    text += &format!("Received:     {} packets, {}, {}\n", stats.dst2src_pkts,
+      format_bytes(stats.dst2src_bytes as f64), format_bits(stats.dst2src_throughput * 8 as f64));
+    text += &format!("Avg rates:    {} (1s), {} (10s), {} (60s), peak {}\n",
+      format_bits(stats.rate_short * 8 as f64), format_bits(stats.rate_medium * 8 as f64),
+      format_bits(stats.rate_long * 8 as f64), format_bits(stats.peak_throughput * 8 as f64));
+    text += &format!("First seen:   {} ({} ago)\n", format_time(&stats.first_seen), format_duration(now.sec - stats.first_seen.sec));
+    text += &format!("Last seen:    {} ({} ago)", format_time(&stats.last_seen), format_duration(now.sec - stats.last_seen.sec));
+    (text, stats.bytes_history())
+  }
+
+  /* the detail text and the throughput history */
+  fn flow_detail(&self, key: &PacketTuple) -> (String, Vec<u64>) {
+    let now = self.snapshot.when;
+    let flow = match self.snapshot.get_flow(key) {
+      Some(flow) => flow,
+      None => return ("The flow has expired".to_string(), Vec::new()),
+    };
+    let stats = &flow.stats;
+
+    let icmp = if !is_icmp(flow.proto) { String::new() } else if flow.sport != 0 {
+      format!(" (echo, id {})", flow.sport)
+    } else {
+      format!(" (type {}, code {})", flow.dport >> 8, flow.dport & 0xff)
+    };
+
+    let vlan = if flow.vlan != 0 { format!(" VLAN {}", flow.vlan) } else { String::new() };
+
+    let mut text = format!("{:?} {} <-> {}{}{}\n",
+      flow.l4proto,
+      format_flow_endpoint(flow.proto, flow.saddr, flow.sport),
+      format_flow_endpoint(flow.proto, flow.daddr, flow.dport),
+      icmp, vlan);
+    text += &format!("Source:       {} [{}]\n", format_flow_endpoint(flow.proto, flow.saddr, flow.sport), flow.smac.to_hex_string());
+    text += &format!("Destination:  {} [{}]\n", format_flow_endpoint(flow.proto, flow.daddr, flow.dport), flow.dmac.to_hex_string());
+    text += &format!("Src -> Dst:   {} packets, {}, {}\n", stats.src2dst_pkts,
+      format_bytes(stats.src2dst_bytes as f64), format_bits(stats.src2dst_throughput * 8 as f64));
+    text += &format!("Dst -> Src:   {} packets, {}, {}\n", stats.dst2src_pkts,
+      format_bytes(stats.dst2src_bytes as f64), format_bits(stats.dst2src_throughput * 8 as f64));
+    text += &format!("Avg rates:    {} (1s), {} (10s), {} (60s), peak {}\n",
+      format_bits(stats.rate_short * 8 as f64), format_bits(stats.rate_medium * 8 as f64),
+      format_bits(stats.rate_long * 8 as f64), format_bits(stats.peak_throughput * 8 as f64));
+    text += &format!("First seen:   {} ({} ago)\n", format_time(&stats.first_seen), format_duration(now.sec - stats.first_seen.sec));
+    text += &format!("Last seen:    {} ({} ago)\n", format_time(&stats.last_seen), format_duration(now.sec - stats.last_seen.sec));
+    if self.has_local_networks {
+      let (up, down) = flow.up_down_bytes();
+      let (up_rate, down_rate) = flow.up_down_throughput();
+
+      text += &match flow.direction() {
+        FlowDirection::Outgoing | FlowDirection::Incoming => format!("Direction:    {}, upload {} ({}), download {} ({})\n",
+          if flow.direction() == FlowDirection::Outgoing { "outgoing" } else { "incoming" },
+          format_bytes(up as f64), format_bits(up_rate * 8 as f64),
+          format_bytes(down as f64), format_bits(down_rate * 8 as f64)),
+        FlowDirection::Internal => "Direction:    internal\n".to_string(),
+        FlowDirection::Transit => "Direction:    transit\n".to_string(),
+      };
+    }
+    if let Some(ref tunnel) = flow.tunnel {
+      text += &format!("Tunnel:       {:?} {} -> {}{}\n", tunnel.kind, tunnel.saddr, tunnel.daddr,
+        if tunnel.id != 0 { format!(" (id {})", tunnel.id) } else { String::new() });
+    }
+
+    text += &format!("Protocol:     {} (master: {}, app: {})\n",
+      flow.protocol, flow.master_protocol, flow.app_protocol);
+    text += &format!("Detection:    {}", if flow.detection_completed { "completed" } else { "in progress" });
+    (text, stats.bytes_history())
+  }
+
+  fn column_title(&self, column: &Column) -> String {
+    if column.sort.is_some() && (column.sort == Some(self.sort)) {
+      format!("{} {}", column.title, if self.reverse { "^" } else { "v" })
+    } else {
+      column.title.to_string()
+    }
+  }
+
+  pub fn draw(&mut self) -> Result<(), io::Error> {
     let size = self.term.size()?;
     let width = size.width;
     let main_style = Style::default().fg(Color::White).bg(Color::Rgb(0,0,0));
     let alt_style = Style::default().fg(Color::Rgb(0,0,0)).bg(Color::White);
+    let selected_style = Style::default().fg(Color::Rgb(0,0,0)).bg(Color::Cyan);
+    let status = self.status_text();
 
-    let perc_w = |perc| -> u16 {
-      (width * perc / 100) as u16
+    let (columns, rows) = match self.view {
+      View::Flows => {
+        let columns = if self.has_local_networks { LOCAL_FLOW_COLUMNS } else { FLOW_COLUMNS };
+        (columns, self.flows_rows())
+      },
+      View::Hosts => {
+        let columns = if self.aggregator.mode == AggregationMode::Host { HOST_COLUMNS } else { GROUP_COLUMNS };
+        (columns, self.hosts_rows())
+      },
+    };
+    let hidden = match self.view {
+      View::Flows => &self.hidden_flow_columns,
+      View::Hosts if self.aggregator.mode == AggregationMode::Host => &self.hidden_host_columns,
+      View::Hosts => &self.hidden_group_columns,
     };
 
-    let to_show = flows.iter().map(|flow| {
-      let v = vec![
-        format!("{}:{}", flow.shost.ip, flow.sport),
-        format!("{}:{}", flow.dhost.ip, flow.dport),
-        format!("{}", handler.get_protocol_name(&flow.protocol)),
-        format_bytes(flow.stats.bytes() as f64),
-        format_bits(flow.stats.throughput * 8 as f64)];
+    let detail = match self.detail {
+      Some(ref detail) => Some(self.detail_pane(detail)),
+      None => None,
+    };
+    let detail_height = if detail.is_some() { DETAIL_HEIGHT } else { 0 };
+    let totals = &self.snapshot.totals;
+    let total_title = format!("Total: {} ({} packets/s)",
+      format_bits(totals.throughput * 8 as f64),
+      totals.history.back().map(|sample| sample.packets as u64).unwrap_or(0));
+    let total_history = totals.bytes_history();
 
-      Row::StyledData(v.into_iter(), &main_style)
+    // keep the selected row within the visible page, the header takes 2 lines
+    self.num_rows = rows.len();
+    self.page_size = cmp::max(size.height as isize - CHART_HEIGHT as isize - 1 - detail_height as isize - 2, 1) as usize;
+    self.selected = cmp::min(self.selected, self.num_rows.saturating_sub(1));
+
+    if self.selected < self.offset {
+      self.offset = self.selected;
+    } else if self.selected >= self.offset + self.page_size {
+      self.offset = self.selected + 1 - self.page_size;
+    }
+
+    let visible: Vec<usize> = (0..columns.len()).filter(|i| !hidden[*i]).collect();
+    let header: Vec<String> = visible.iter().map(|i| self.column_title(&columns[*i])).collect();
+    let widths: Vec<u16> = visible.iter().map(|i| {
+      (width * columns[*i].width / 100) as u16
+    }).collect();
+    let rows: Vec<(usize, Vec<&String>)> = rows.iter().enumerate()
+      .skip(self.offset)
+      .take(self.page_size)
+      .map(|(idx, row)| (idx, visible.iter().map(|i| &row[*i]).collect()))
+      .collect();
+
+    let selected = self.selected;
+    let to_show = rows.iter().map(|&(idx, ref row)| {
+      Row::StyledData(row.iter(), if idx == selected { &selected_style } else { &main_style })
     });
 
-    Table::new(
-        ["Source", "Destination", "Proto", "Traffic", "Thpt"].into_iter(),
-        to_show
-    )
-    .block(Block::default())
-    .header_style(alt_style)
-    .widths(&[perc_w(25), perc_w(25), perc_w(20), perc_w(10), perc_w(10)])
-    .style(main_style)
-    .column_spacing(1)
-    .render(&mut self.term, &size);
+    Group::default()
+      .direction(Direction::Vertical)
+      .sizes(&[Size::Fixed(CHART_HEIGHT), Size::Min(0), Size::Fixed(detail_height), Size::Fixed(1)])
+      .render(&mut self.term, &size, |t, chunks| {
+        Sparkline::default()
+          .block(Block::default().borders(border::ALL).title(&total_title))
+          .data(&total_history)
+          .style(main_style)
+          .render(t, &chunks[0]);
+
+        Table::new(header.iter(), to_show)
+          .block(Block::default())
+          .header_style(alt_style)
+          .widths(&widths)
+          .style(main_style)
+          .column_spacing(1)
+          .render(t, &chunks[1]);
+
+        if let Some((title, ref text, ref history)) = detail {
+          Group::default()
+            .direction(Direction::Horizontal)
+            .sizes(&[Size::Percent(60), Size::Percent(40)])
+            .render(t, &chunks[2], |t, chunks| {
+              Paragraph::default()
+                .block(Block::default().borders(border::ALL).title(title))
+                .text(text)
+                .style(main_style)
+                .render(t, &chunks[0]);
+
+              Sparkline::default()
+                .block(Block::default().borders(border::ALL).title("Throughput"))
+                .data(history)
+                .style(main_style)
+                .render(t, &chunks[1]);
+            });
+        }
+
+        Paragraph::default()
+          .text(&status)
+          .style(alt_style)
+          .render(t, &chunks[3]);
+      });
 
     self.term.draw()
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use engine::types::{IPPROTO_TCP, IPPROTO_ICMP, IPPROTO_ICMPV6};
+
+  fn ip(addr: &str) -> IpAddr {
+    addr.parse().unwrap()
+  }
+
+  #[test]
+  fn flow_endpoints() {
+    assert_eq!(format_flow_endpoint(IPPROTO_TCP, ip("10.0.0.1"), 443), "10.0.0.1:443");
+    // IPv6 addresses are bracketed, so that the port is not read as a group
+    assert_eq!(format_flow_endpoint(IPPROTO_TCP, ip("2001:db8::1"), 443), "[2001:db8::1]:443");
+    assert_eq!(format_flow_endpoint(IPPROTO_TCP, ip("::ffff:10.0.0.1"), 80), "[::ffff:10.0.0.1]:80");
+    // no port for the protocols without one
+    assert_eq!(format_flow_endpoint(IPPROTO_ICMP, ip("10.0.0.1"), 8), "10.0.0.1");
+    assert_eq!(format_flow_endpoint(IPPROTO_ICMPV6, ip("2001:db8::1"), 8), "2001:db8::1");
+  }
+
+  #[test]
+  fn hosts() {
+    assert_eq!(format_host(ip("2001:db8::1"), 0), "2001:db8::1");
+    assert_eq!(format_host(ip("10.0.0.1"), 100), "10.0.0.1 vlan 100");
+  }
+}